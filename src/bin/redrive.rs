@@ -0,0 +1,113 @@
+//! Replays dead-lettered messages back to their original subjects.
+//!
+//! ```text
+//! svc-nats-redrive --url <url> --stream <dead-letter stream> [--creds <path>]
+//!     [--prefix terminated] [--subject <filter>] [--classroom-id <uuid>]
+//!     [--since <rfc3339>] [--until <rfc3339>] [--rate <messages per second>] [--dry-run]
+//! ```
+
+use std::process::ExitCode;
+
+use anyhow::{anyhow, Context, Result};
+use svc_nats_client::{
+    redrive::{self, RedriveOptions},
+    Auth, Client, Config, DeadLetterConfig,
+};
+
+struct Args {
+    config: Config,
+    options: RedriveOptions,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut url = None;
+    let mut creds = None;
+    let mut stream = None;
+    let mut prefix = "terminated".to_string();
+    let mut options = RedriveOptions::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--dry-run" {
+            options.dry_run = true;
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("missing value for `{arg}`"))?;
+
+        match arg.as_str() {
+            "--url" => url = Some(value),
+            "--creds" => creds = Some(value),
+            "--stream" => stream = Some(value),
+            "--prefix" => prefix = value,
+            "--subject" => options.subject = Some(value),
+            "--classroom-id" => {
+                options.classroom_id = Some(value.parse().context("invalid --classroom-id")?)
+            }
+            "--since" => {
+                options.since = Some(humantime::parse_rfc3339(&value).context("invalid --since")?)
+            }
+            "--until" => {
+                options.until = Some(humantime::parse_rfc3339(&value).context("invalid --until")?)
+            }
+            "--rate" => options.rate_limit = Some(value.parse().context("invalid --rate")?),
+            _ => return Err(anyhow!("unknown argument `{arg}`")),
+        }
+    }
+
+    let config = Config {
+        url: url.ok_or_else(|| anyhow!("--url is required"))?,
+        auth: match creds {
+            Some(path) => Auth::CredentialsFile { path },
+            None => Auth::None,
+        },
+        tls: None,
+        connection: Default::default(),
+        subscribe_durable: None,
+        subscribe_ephemeral: None,
+        dead_letter: Some(DeadLetterConfig {
+            stream: stream.ok_or_else(|| anyhow!("--stream is required"))?,
+            subject_prefix: prefix,
+        }),
+        consumers: Default::default(),
+        provision: None,
+    };
+
+    Ok(Args { config, options })
+}
+
+async fn run() -> Result<()> {
+    let args = parse_args()?;
+
+    let client = Client::new(args.config)
+        .await
+        .context("failed to connect to nats")?;
+    let report = redrive::run(&client, &args.options).await?;
+
+    println!(
+        "matched: {}, republished: {}, skipped: {}{}",
+        report.matched,
+        report.republished,
+        report.skipped,
+        if args.options.dry_run {
+            " (dry run)"
+        } else {
+            ""
+        }
+    );
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,53 +1,121 @@
 use crate::{
-    event::{Builder as EventBuilder, Event},
-    headers::{HeaderError, Headers},
+    config::{Auth, ConnectionConfig, ReconnectDelay},
+    dead_letter::{self, DeadLetter},
+    event::Event,
+    headers::HeaderError,
     subject::{Subject, SubjectError, TERMINATED_PREFIX},
-    Config, MessageStream, Messages, NatsClient,
+    tls, Config, CoreMessage, CoreMessageStream, CoreNatsClient, MessageStream, Messages,
+    NatsClient,
 };
 use anyhow::anyhow;
 
 use async_nats::{
+    client::FlushError,
+    connection::State as ConnectionState,
     jetstream::{
         consumer::{self, AckPolicy, DeliverPolicy, PullConsumer, PushConsumer, StreamError},
-        context::{GetStreamError, PublishError as NatsPublishError},
+        context::{
+            AccountError, GetStreamError, PublishAckFuture, PublishError as NatsPublishError,
+            PublishErrorKind,
+        },
+        publish::PublishAck,
         stream::ConsumerError,
         AckKind, Context, Message,
     },
-    Client as AsyncNatsClient, ConnectError, Error, Event as NatsEvent,
+    Client as AsyncNatsClient, ConnectError as NatsConnectError, ConnectOptions, Error,
+    Event as NatsEvent, HeaderMap, PublishError as NatsCorePublishError, Request,
+    RequestError as NatsRequestError, RequestErrorKind, ServerAddr,
+    SubscribeError as NatsSubscribeError,
 };
-use std::str::FromStr;
-use std::sync::Arc;
+use bytes::Bytes;
+
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use std::{collections::VecDeque, path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, watch};
 use tracing::{error, warn};
 
+const CONNECTION_EVENTS_CAPACITY: usize = 64;
+
+pub type ConnectionEvent = NatsEvent;
+
 #[derive(Clone)]
 pub struct Client {
     inner: AsyncNatsClient,
     jetstream: Context,
     config: Config,
+    state: watch::Receiver<ConnectionState>,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+/// Result of a successful `Client::health` check.
+#[derive(Debug, Clone)]
+pub struct Health {
+    pub server_name: String,
+    pub server_version: String,
+    pub streams: usize,
+    pub consumers: usize,
 }
 
 impl Client {
     pub async fn new(config: Config) -> Result<Self, ConnectError> {
-        let creds: &str = config.creds.as_ref();
-        let client = async_nats::ConnectOptions::with_credentials_file(creds.into())
-            .await?
-            .event_callback(|event| async move {
-                let error = match event {
-                    NatsEvent::ServerError(err) => anyhow!(err),
-                    NatsEvent::ClientError(err) => anyhow!(err),
-                    event => {
-                        warn!(%event, "nats connection status");
-                        return;
+        let mut options = connect_options(&config.auth).await?;
+        if let Some(tls) = &config.tls {
+            options = tls::configure(options, tls)?;
+        }
+        options = configure_connection(options, &config.connection);
+
+        let servers = std::iter::once(&config.url)
+            .chain(&config.connection.servers)
+            .map(|url| {
+                url.parse::<ServerAddr>()
+                    .map_err(|err| ConnectError::InvalidServerAddr(url.to_owned(), err))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (state_tx, state_rx) = watch::channel(ConnectionState::Pending);
+        let state_tx = Arc::new(state_tx);
+        let (events_tx, _) = broadcast::channel(CONNECTION_EVENTS_CAPACITY);
+
+        let callback_state_tx = state_tx.clone();
+        let callback_events_tx = events_tx.clone();
+        let client = options
+            .event_callback(move |event| {
+                let state_tx = callback_state_tx.clone();
+                let events_tx = callback_events_tx.clone();
+                async move {
+                    match event {
+                        NatsEvent::Connected => {
+                            state_tx.send_replace(ConnectionState::Connected);
+                        }
+                        NatsEvent::Disconnected => {
+                            state_tx.send_replace(ConnectionState::Disconnected);
+                        }
+                        _ => {}
                     }
-                };
+                    // Nobody may be listening
+                    let _ = events_tx.send(event.clone());
+
+                    let error = match event {
+                        NatsEvent::ServerError(err) => anyhow!(err),
+                        NatsEvent::ClientError(err) => anyhow!(err),
+                        event => {
+                            warn!(%event, "nats connection status");
+                            return;
+                        }
+                    };
 
-                error!(%error);
-                if let Err(err) = svc_error::extension::sentry::send(Arc::new(error)) {
-                    error!(%err);
+                    error!(%error);
+                    if let Err(err) = svc_error::extension::sentry::send(Arc::new(error)) {
+                        error!(%err);
+                    }
                 }
             })
-            .connect(&config.url)
-            .await?;
+            .connect(servers)
+            .await
+            .map_err(ConnectError::ConnectionFailed)?;
+
+        state_tx.send_replace(client.connection_state());
 
         let jetstream = async_nats::jetstream::new(client.clone());
 
@@ -55,8 +123,212 @@ impl Client {
             inner: client,
             jetstream,
             config,
+            state: state_rx,
+            events: events_tx,
+        })
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.inner.connection_state()
+    }
+
+    /// Returns a receiver which is notified when the client connects or disconnects.
+    pub fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    /// Returns a receiver of all connection events, including server and client errors.
+    pub fn connection_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Checks the connection to the server and JetStream availability,
+    /// suitable for readiness probes.
+    pub async fn health(&self) -> Result<Health, HealthError> {
+        let state = self.inner.connection_state();
+        if state != ConnectionState::Connected {
+            return Err(HealthError::NotConnected(state));
+        }
+
+        self.inner.flush().await.map_err(HealthError::FlushFailed)?;
+
+        let account = self
+            .jetstream
+            .query_account()
+            .await
+            .map_err(HealthError::JetStreamUnavailable)?;
+        let server = self.inner.server_info();
+
+        Ok(Health {
+            server_name: server.server_name,
+            server_version: server.version,
+            streams: account.streams,
+            consumers: account.consumers,
         })
     }
+
+    /// Returns a client whose `subscribe_durable` uses the named subscription
+    /// from `Config::consumers`.
+    pub fn with_durable(&self, name: &str) -> Result<Self, SubscribeError> {
+        let subscribe_durable = self
+            .config
+            .consumers
+            .get(name)
+            .cloned()
+            .ok_or(SubscribeError::SubscribeConfigNotFound)?;
+
+        let mut client = self.clone();
+        client.config.subscribe_durable = Some(subscribe_durable);
+        Ok(client)
+    }
+
+    pub(crate) fn jetstream(&self) -> &Context {
+        &self.jetstream
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub(crate) async fn publish_with_headers(
+        &self,
+        subject: String,
+        headers: HeaderMap,
+        payload: Bytes,
+    ) -> Result<PublishAck, PublishError> {
+        self.send(subject, headers, payload).await.wait().await
+    }
+
+    /// Publishes the message without waiting for the ack.
+    async fn send(&self, subject: String, headers: HeaderMap, payload: Bytes) -> PendingAck {
+        #[cfg(feature = "metrics")]
+        let (prefix, started) = (
+            subject.split('.').next().unwrap_or_default().to_owned(),
+            std::time::Instant::now(),
+        );
+
+        let ack = self
+            .jetstream
+            .publish_with_headers(subject, headers, payload)
+            .await
+            .map_err(PublishError::PublishFailed);
+
+        PendingAck {
+            ack,
+            #[cfg(feature = "metrics")]
+            prefix,
+            #[cfg(feature = "metrics")]
+            started,
+        }
+    }
+}
+
+async fn connect_options(auth: &Auth) -> Result<ConnectOptions, ConnectError> {
+    let options = match auth {
+        Auth::None => ConnectOptions::new(),
+        Auth::CredentialsFile { path } => ConnectOptions::with_credentials_file(path.into())
+            .await
+            .map_err(ConnectError::InvalidCredentials)?,
+        Auth::Credentials { env } => {
+            let creds = std::env::var(env)
+                .map_err(|_| ConnectError::CredentialsEnvNotFound(env.to_owned()))?;
+            ConnectOptions::with_credentials(&creds).map_err(ConnectError::InvalidCredentials)?
+        }
+        Auth::Token { token } => ConnectOptions::with_token(token.to_owned()),
+        Auth::UserPassword { user, password } => {
+            ConnectOptions::with_user_and_password(user.to_owned(), password.to_owned())
+        }
+        Auth::Nkey { seed } => ConnectOptions::with_nkey(seed.to_owned()),
+    };
+
+    Ok(options)
+}
+
+fn configure_connection(mut options: ConnectOptions, config: &ConnectionConfig) -> ConnectOptions {
+    if let Some(timeout) = config.connection_timeout {
+        options = options.connection_timeout(timeout);
+    }
+    if let Some(interval) = config.ping_interval {
+        options = options.ping_interval(interval);
+    }
+    if let Some(ReconnectDelay { initial, max }) = config.reconnect_delay {
+        options = options.reconnect_delay_callback(move |attempts| {
+            let attempts = u32::try_from(attempts).unwrap_or(u32::MAX);
+            std::cmp::min(initial.saturating_mul(2_u32.saturating_pow(attempts)), max)
+        });
+    }
+    if config.retry_on_initial_connect {
+        options = options.retry_on_initial_connect();
+    }
+    if let Some(name) = &config.name {
+        options = options.name(name);
+    }
+    if let Some(prefix) = &config.inbox_prefix {
+        options = options.custom_inbox_prefix(prefix);
+    }
+
+    options
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("invalid server address `{0}`: `{1}`")]
+    InvalidServerAddr(String, std::io::Error),
+    #[error("environment variable `{0}` with credentials is not set")]
+    CredentialsEnvNotFound(String),
+    #[error("invalid credentials: `{0}`")]
+    InvalidCredentials(std::io::Error),
+    #[error("failed to read `{0}`: `{1}`")]
+    TlsFileUnreadable(PathBuf, std::io::Error),
+    #[error("invalid `{0}`: {1}")]
+    InvalidTlsFile(PathBuf, &'static str),
+    #[error("client certificate and key must be set together")]
+    IncompleteClientCertificate,
+    #[error("failed to connect: `{0}`")]
+    ConnectionFailed(NatsConnectError),
+}
+
+/// A published message waiting for the JetStream ack.
+struct PendingAck {
+    ack: Result<PublishAckFuture, PublishError>,
+    #[cfg(feature = "metrics")]
+    prefix: String,
+    #[cfg(feature = "metrics")]
+    started: std::time::Instant,
+}
+
+impl PendingAck {
+    async fn wait(self) -> Result<PublishAck, PublishError> {
+        let result = match self.ack {
+            Ok(ack) => ack.await.map_err(|err| match err.kind() {
+                PublishErrorKind::WrongLastSequence => PublishError::WrongLastSequence(err),
+                _ => PublishError::AckFailed(err),
+            }),
+            Err(err) => Err(err),
+        };
+
+        #[cfg(feature = "metrics")]
+        match &result {
+            Ok(_) => metrics::PUBLISH_DURATION
+                .with_label_values(&[&self.prefix])
+                .observe(self.started.elapsed().as_secs_f64()),
+            Err(_) => metrics::PUBLISH_ERRORS
+                .with_label_values(&[&self.prefix])
+                .inc(),
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HealthError {
+    #[error("nats client is not connected: `{0}`")]
+    NotConnected(ConnectionState),
+    #[error("failed to flush connection: `{0}`")]
+    FlushFailed(FlushError),
+    #[error("jetstream is unavailable: `{0}`")]
+    JetStreamUnavailable(AccountError),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -65,6 +337,13 @@ pub enum PublishError {
     PublishFailed(NatsPublishError),
     #[error("failed to ack message: `{0}`")]
     AckFailed(NatsPublishError),
+    /// The stream's last sequence didn't match the expected one, the state should be reloaded.
+    #[error("wrong last sequence: `{0}`")]
+    WrongLastSequence(NatsPublishError),
+    #[error("failed to publish core message: `{0}`")]
+    CorePublishFailed(NatsCorePublishError),
+    #[error("reply subject is not found")]
+    ReplySubjectNotFound,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -79,6 +358,28 @@ pub enum SubscribeError {
     StreamCreationFailed(StreamError),
     #[error("failed to create ephemeral consumer: `{0}`")]
     EphemeralConsumerCreationFailed(ConsumerError),
+    #[error("failed to subscribe: `{0}`")]
+    CoreSubscriptionFailed(NatsSubscribeError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RequestError {
+    #[error("request timed out")]
+    TimedOut,
+    #[error("no responders for the request")]
+    NoResponders,
+    #[error("failed to send request: `{0}`")]
+    RequestFailed(NatsRequestError),
+}
+
+impl From<NatsRequestError> for RequestError {
+    fn from(err: NatsRequestError) -> Self {
+        match err.kind() {
+            RequestErrorKind::TimedOut => Self::TimedOut,
+            RequestErrorKind::NoResponders => Self::NoResponders,
+            RequestErrorKind::Other => Self::RequestFailed(err),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -89,25 +390,56 @@ pub enum TermMessageError {
     InvalidSubject(#[from] SubjectError),
     #[error(transparent)]
     PublishError(#[from] PublishError),
+    #[error("dead letter to `{0}` was discarded as a duplicate")]
+    DuplicateDeadLetter(String),
     #[error("failed to term message: `{0}`")]
     AckTermFailed(Error),
 }
 
 #[async_trait::async_trait]
 impl NatsClient for Client {
-    async fn publish(&self, event: &Event) -> Result<(), PublishError> {
-        self.jetstream
-            .publish_with_headers(
-                event.subject().to_string(),
-                event.headers().to_owned().into(),
-                event.payload().to_owned().into(),
-            )
-            .await
-            .map_err(PublishError::PublishFailed)?
-            .await
-            .map_err(PublishError::AckFailed)?;
+    async fn publish(&self, event: &Event) -> Result<PublishAck, PublishError> {
+        self.publish_with_headers(
+            event.subject().to_string(),
+            event.headers().to_owned().into(),
+            event.payload().to_owned().into(),
+        )
+        .await
+    }
+
+    /// Sends the events without waiting for each ack, at most `max_pending` acks
+    /// are awaited at the same time.
+    async fn publish_batch(
+        &self,
+        events: &[Event],
+        max_pending: usize,
+    ) -> Vec<Result<PublishAck, PublishError>> {
+        let mut results = Vec::with_capacity(events.len());
+        let mut pending: VecDeque<PendingAck> =
+            VecDeque::with_capacity(max_pending.min(events.len()));
+
+        for event in events {
+            if pending.len() >= max_pending.max(1) {
+                if let Some(ack) = pending.pop_front() {
+                    results.push(ack.wait().await);
+                }
+            }
+
+            let ack = self
+                .send(
+                    event.subject().to_string(),
+                    event.headers().to_owned().into(),
+                    event.payload().to_owned().into(),
+                )
+                .await;
+            pending.push_back(ack);
+        }
+
+        for ack in pending {
+            results.push(ack.wait().await);
+        }
 
-        Ok(())
+        results
     }
 
     /// Returns a stream of messages for Durable Pull Consumer.
@@ -137,7 +469,7 @@ impl NatsClient for Client {
             .await
             .map_err(SubscribeError::StreamCreationFailed)?;
 
-        Ok(MessageStream(stream))
+        Ok(MessageStream::from(stream))
     }
 
     /// Returns a stream of messages for Ephemeral Push Consumer.
@@ -186,23 +518,30 @@ impl NatsClient for Client {
         Ok(messages)
     }
 
-    async fn terminate(&self, message: &Message) -> Result<(), TermMessageError> {
-        let headers = Headers::try_from(message.headers.clone().unwrap_or_default())?;
-        let old_subject = Subject::from_str(&message.subject)?;
-        let new_subject = Subject::new(
-            format!("{}.{}", TERMINATED_PREFIX, old_subject.prefix()),
-            old_subject.classroom_id(),
-            old_subject.entity_type().into(),
-        );
-        let event = EventBuilder::new(
-            new_subject,
-            message.payload.to_vec(),
-            headers.event_id().clone(),
-            headers.sender_id().clone(),
-        )
-        .build();
+    async fn terminate(&self, message: &Message, reason: &str) -> Result<(), TermMessageError> {
+        let prefix = self
+            .config
+            .dead_letter
+            .as_ref()
+            .map(|config| config.subject_prefix.as_str())
+            .unwrap_or(TERMINATED_PREFIX);
+        let subject = format!("{}.{}", prefix, message.subject);
 
-        self.publish(&event).await?;
+        // Keep the original headers so the message can be replayed as is
+        let mut headers = message
+            .headers
+            .as_ref()
+            .map(dead_letter::dead_letter_headers)
+            .unwrap_or_default();
+        DeadLetter::new(message, reason).insert_into(&mut headers);
+
+        let ack = self
+            .publish_with_headers(subject.clone(), headers, message.payload.clone())
+            .await?;
+        // The stream dropped the dead letter, so the message must not be terminated
+        if ack.duplicate {
+            return Err(TermMessageError::DuplicateDeadLetter(subject));
+        }
 
         message
             .ack_with(AckKind::Term)
@@ -210,3 +549,70 @@ impl NatsClient for Client {
             .map_err(TermMessageError::AckTermFailed)
     }
 }
+
+#[async_trait::async_trait]
+impl CoreNatsClient for Client {
+    async fn publish_core(&self, event: &Event) -> Result<(), PublishError> {
+        self.inner
+            .publish_with_headers(
+                event.subject().to_string(),
+                event.headers().to_owned().into(),
+                event.payload().to_owned().into(),
+            )
+            .await
+            .map_err(PublishError::CorePublishFailed)
+    }
+
+    async fn subscribe_core(
+        &self,
+        subject: &str,
+        queue_group: Option<&str>,
+    ) -> Result<CoreMessageStream, SubscribeError> {
+        let subscriber = match queue_group {
+            Some(queue_group) => {
+                self.inner
+                    .queue_subscribe(subject.to_owned(), queue_group.to_owned())
+                    .await
+            }
+            None => self.inner.subscribe(subject.to_owned()).await,
+        };
+
+        subscriber
+            .map(CoreMessageStream::from)
+            .map_err(SubscribeError::CoreSubscriptionFailed)
+    }
+
+    async fn request_with_headers(
+        &self,
+        subject: String,
+        headers: HeaderMap,
+        payload: Bytes,
+        timeout: Duration,
+    ) -> Result<CoreMessage, RequestError> {
+        let request = Request::new()
+            .headers(headers)
+            .payload(payload)
+            .timeout(Some(timeout));
+
+        let response = self.inner.send_request(subject, request).await?;
+
+        Ok(response)
+    }
+
+    async fn respond_with_headers(
+        &self,
+        request: &CoreMessage,
+        headers: HeaderMap,
+        payload: Bytes,
+    ) -> Result<(), PublishError> {
+        let reply = request
+            .reply
+            .clone()
+            .ok_or(PublishError::ReplySubjectNotFound)?;
+
+        self.inner
+            .publish_with_headers(reply, headers, payload)
+            .await
+            .map_err(PublishError::CorePublishFailed)
+    }
+}
@@ -1,12 +1,157 @@
+use async_nats::jetstream::stream::RetentionPolicy;
 use serde::Deserialize;
-use std::time::Duration;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "ConfigRepr")]
 pub struct Config {
     pub url: String,
-    pub creds: String,
+    pub auth: Auth,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub connection: ConnectionConfig,
     pub subscribe_durable: Option<SubscribeDurableConfig>,
     pub subscribe_ephemeral: Option<SubscribeEphemeralConfig>,
+    pub dead_letter: Option<DeadLetterConfig>,
+    /// Named durable subscriptions, see `supervisor::Supervisor`.
+    #[serde(default)]
+    pub consumers: HashMap<String, SubscribeDurableConfig>,
+    /// Streams and consumers created by `Client::provision`.
+    pub provision: Option<ProvisionConfig>,
+}
+
+/// `Config` as it's written, with the legacy `creds` field.
+#[derive(Deserialize)]
+struct ConfigRepr {
+    url: String,
+    auth: Option<Auth>,
+    /// Path to a `.creds` file, same as `Auth::CredentialsFile`.
+    creds: Option<String>,
+    tls: Option<TlsConfig>,
+    #[serde(default)]
+    connection: ConnectionConfig,
+    subscribe_durable: Option<SubscribeDurableConfig>,
+    subscribe_ephemeral: Option<SubscribeEphemeralConfig>,
+    dead_letter: Option<DeadLetterConfig>,
+    #[serde(default)]
+    consumers: HashMap<String, SubscribeDurableConfig>,
+    provision: Option<ProvisionConfig>,
+}
+
+impl TryFrom<ConfigRepr> for Config {
+    type Error = &'static str;
+
+    fn try_from(repr: ConfigRepr) -> Result<Self, Self::Error> {
+        let auth = match (repr.auth, repr.creds) {
+            (Some(auth), None) => auth,
+            (None, Some(path)) => Auth::CredentialsFile { path },
+            (Some(_), Some(_)) => return Err("`auth` and `creds` can't be set together"),
+            (None, None) => return Err("missing field `auth`"),
+        };
+
+        Ok(Self {
+            url: repr.url,
+            auth,
+            tls: repr.tls,
+            connection: repr.connection,
+            subscribe_durable: repr.subscribe_durable,
+            subscribe_ephemeral: repr.subscribe_ephemeral,
+            dead_letter: repr.dead_letter,
+            consumers: repr.consumers,
+            provision: repr.provision,
+        })
+    }
+}
+
+/// How the client authenticates to the server.
+#[derive(Clone, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Auth {
+    /// No authentication, e.g. for a local dev server.
+    None,
+    /// Path to a `.creds` file.
+    CredentialsFile {
+        path: String,
+    },
+    /// Name of an environment variable with the content of a `.creds` file.
+    Credentials {
+        env: String,
+    },
+    Token {
+        token: String,
+    },
+    UserPassword {
+        user: String,
+        password: String,
+    },
+    Nkey {
+        seed: String,
+    },
+}
+
+/// Secrets are redacted.
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const REDACTED: &str = "<redacted>";
+
+        match self {
+            Self::None => write!(f, "None"),
+            Self::CredentialsFile { path } => f
+                .debug_struct("CredentialsFile")
+                .field("path", path)
+                .finish(),
+            Self::Credentials { env } => f.debug_struct("Credentials").field("env", env).finish(),
+            Self::Token { .. } => f.debug_struct("Token").field("token", &REDACTED).finish(),
+            Self::UserPassword { user, .. } => f
+                .debug_struct("UserPassword")
+                .field("user", user)
+                .field("password", &REDACTED)
+                .finish(),
+            Self::Nkey { .. } => f.debug_struct("Nkey").field("seed", &REDACTED).finish(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ConnectionConfig {
+    /// Servers used in addition to `Config::url`.
+    #[serde(default)]
+    pub servers: Vec<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub connection_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub ping_interval: Option<Duration>,
+    #[serde(default)]
+    pub reconnect_delay: Option<ReconnectDelay>,
+    /// Keep trying to connect if the server is unavailable on startup.
+    #[serde(default)]
+    pub retry_on_initial_connect: bool,
+    /// Client name shown in the server's connection info.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub inbox_prefix: Option<String>,
+}
+
+/// Exponential backoff between reconnect attempts.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReconnectDelay {
+    #[serde(with = "humantime_serde")]
+    pub initial: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max: Duration,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    /// Refuse to connect to servers without TLS.
+    #[serde(default)]
+    pub required: bool,
+    /// PEM file with root certificates to verify the server.
+    pub ca: Option<PathBuf>,
+    /// PEM files with the client certificate and its private key for mTLS.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -24,6 +169,54 @@ pub struct SubscribeEphemeralConfig {
     pub consumer_prefix: String,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeadLetterConfig {
+    /// Stream which stores the dead-lettered messages.
+    pub stream: String,
+    /// Dead-lettered messages are published to `<subject_prefix>.<original subject>`.
+    pub subject_prefix: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProvisionConfig {
+    /// Update the streams and consumers which differ from the declared config,
+    /// otherwise the differences are only reported.
+    #[serde(default)]
+    pub update: bool,
+    pub streams: Vec<StreamConfig>,
+}
+
+/// Declared stream config, the fields which are not set are not checked.
+#[derive(Clone, Debug, Deserialize)]
+pub struct StreamConfig {
+    pub name: String,
+    pub subjects: Vec<String>,
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+    #[serde(default)]
+    pub replicas: Option<usize>,
+    #[serde(default, with = "humantime_serde")]
+    pub duplicate_window: Option<Duration>,
+    #[serde(default)]
+    pub consumers: Vec<PullConsumerConfig>,
+}
+
+/// Declared durable pull consumer config, the fields which are not set are not checked.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PullConsumerConfig {
+    pub name: String,
+    #[serde(default)]
+    pub filter_subject: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub ack_wait: Option<Duration>,
+    #[serde(default)]
+    pub max_deliver: Option<i64>,
+    #[serde(default)]
+    pub max_ack_pending: Option<i64>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct ConsumerConfig {
     #[serde(with = "humantime_serde")]
@@ -34,4 +227,57 @@ pub struct ConsumerConfig {
     pub suspend_sentry_interval: Duration,
     #[serde(with = "humantime_serde")]
     pub resubscribe_interval: Duration,
+    /// Max number of messages handled at the same time.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    #[serde(default)]
+    pub redelivery: RedeliveryMode,
+    /// Messages which still fail with a transient error on this delivery attempt
+    /// are terminated and sent to the dead-letter subject.
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    /// Sends `AckKind::Progress` with this interval while the handler is running
+    /// or the message is queued behind another one of its partition,
+    /// should be less than the consumer's `ack_wait`. Queued messages get progress acks
+    /// every 10 seconds if it's not set.
+    #[serde(default, with = "humantime_serde")]
+    pub progress_interval: Option<Duration>,
+    /// Handlers running longer than this fail with a transient error.
+    #[serde(default, with = "humantime_serde")]
+    pub handler_timeout: Option<Duration>,
+    /// How a panic in the handler is treated.
+    #[serde(default)]
+    pub panic_failure: PanicFailure,
+    /// How long to wait for running handlers on shutdown, 30 seconds by default.
+    #[serde(default = "default_drain_timeout", with = "humantime_serde")]
+    pub drain_timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PanicFailure {
+    /// The message is redelivered.
+    Transient,
+    /// The message is terminated and sent to the dead-letter subject.
+    #[default]
+    Permanent,
+}
+
+/// What the consumer does when a handler returns a transient failure.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RedeliveryMode {
+    /// NAK the message and suspend the whole consumer with an exponential backoff.
+    #[default]
+    Suspend,
+    /// NAK the message with a delay based on its delivery count, the consumer keeps going.
+    Delay,
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
 }
@@ -1,13 +1,26 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet, VecDeque},
+    hash::Hash,
+    panic::AssertUnwindSafe,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
-use futures_util::StreamExt;
+use futures_util::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
 use svc_error::extension::sentry;
 use tokio::{sync::watch, task::JoinHandle, time::Instant};
+use tracing::Instrument;
+use uuid::Uuid;
+
+#[cfg(feature = "metrics")]
+use crate::metrics;
 
 use crate::{
-    config::ConsumerConfig, AckKind as NatsAckKind, Client, Message, MessageStream, NatsClient,
-    SubscribeError,
+    config::{ConsumerConfig, PanicFailure, RedeliveryMode},
+    AckKind as NatsAckKind, ConsumerMessage, MessageStream, NatsClient, Subject, SubscribeError,
 };
 
 #[derive(Debug)]
@@ -16,6 +29,8 @@ pub enum Error {
     StreamClosed,
     InternalError(anyhow::Error),
     HandleMessageError(anyhow::Error),
+    MaxAttemptsReached(anyhow::Error),
+    HandlerPanicked(anyhow::Error),
 }
 
 impl std::fmt::Display for Error {
@@ -25,16 +40,88 @@ impl std::fmt::Display for Error {
             Error::StreamClosed => write!(f, "nats stream was closed"),
             Error::InternalError(e) => write!(f, "internal nats error: {e}"),
             Error::HandleMessageError(e) => write!(f, "handle message error: {e}"),
+            Error::MaxAttemptsReached(e) => {
+                write!(f, "max delivery attempts reached, last error: {e}")
+            }
+            Error::HandlerPanicked(e) => write!(f, "handler panicked: {e}"),
         }
     }
 }
 
 enum HandleMessageOutcome {
     Processed,
-    ProcessLater,
+    ProcessLater(Redelivery),
     WontProcess,
 }
 
+/// A message which was NAKed to be handled later.
+struct Redelivery {
+    stream_sequence: Option<u64>,
+    delay: Option<Duration>,
+}
+
+/// A partition whose message is waiting for redelivery. Later messages of the partition
+/// stay queued until it comes back, so they aren't handled before it.
+struct Hold {
+    stream_sequence: u64,
+    until: Instant,
+}
+
+/// How long the stream must stay silent before it's dropped on shutdown.
+const DRAIN_QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+/// Progress ack interval for queued messages if `ConsumerConfig::progress_interval` isn't set.
+const QUEUED_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What a message handler returns.
+pub type HandleResult = Result<(), HandleMessageFailure<anyhow::Error>>;
+
+pub(crate) type BoxHandler<M> =
+    Box<dyn Fn(Arc<M>) -> BoxFuture<'static, HandleResult> + Send + Sync>;
+
+pub(crate) fn box_handler<M, H, Fut>(handler: H) -> BoxHandler<M>
+where
+    H: Fn(Arc<M>) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = HandleResult> + Send + 'static,
+{
+    Box::new(move |message| Box::pin(handler(message)))
+}
+
+/// A handler composed of other handlers, like a router or a middleware stack.
+#[async_trait::async_trait]
+pub(crate) trait ComposedHandler<M>: Send + Sync + 'static {
+    async fn handle(&self, message: Arc<M>) -> HandleResult;
+
+    /// Turns the composed handler into a handler for [`run`].
+    fn into_handler(self) -> BoxHandler<M>
+    where
+        Self: Sized,
+        M: Send + Sync + 'static,
+    {
+        let handler = Arc::new(self);
+
+        Box::new(move |message| {
+            let handler = handler.clone();
+            Box::pin(async move { handler.handle(message).await })
+        })
+    }
+
+    /// Runs a durable consumer with the composed handler.
+    fn run<C>(
+        self,
+        nats_client: C,
+        cfg: ConsumerConfig,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> JoinHandle<Result<ShutdownSummary, SubscribeError>>
+    where
+        Self: Sized,
+        C: NatsClient<M> + 'static,
+        M: ConsumerMessage,
+    {
+        run(nats_client, cfg, shutdown_rx, self.into_handler())
+    }
+}
+
 #[derive(Debug)]
 pub enum HandleMessageFailure<E> {
     Transient(E),
@@ -85,29 +172,65 @@ impl<T, E> FailureKindExt<T, E> for Result<T, HandleMessageFailure<E>> {
     }
 }
 
-pub fn run<H, Fut>(
-    nats_client: Client,
+/// Runs a durable consumer. Messages of the same classroom are handled in order,
+/// messages of different classrooms are handled concurrently up to `cfg.concurrency`.
+pub fn run<C, M, H, Fut>(
+    nats_client: C,
     cfg: ConsumerConfig,
     shutdown_rx: watch::Receiver<()>,
     handle_message: H,
-) -> JoinHandle<Result<(), SubscribeError>>
+) -> JoinHandle<Result<ShutdownSummary, SubscribeError>>
 where
-    H: Fn(Arc<Message>) -> Fut + Send + Sync + 'static,
+    C: NatsClient<M> + 'static,
+    M: ConsumerMessage,
+    H: Fn(Arc<M>) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<(), HandleMessageFailure<anyhow::Error>>>
+        + std::marker::Send,
+{
+    run_partitioned(
+        nats_client,
+        cfg,
+        shutdown_rx,
+        classroom_id::<M>,
+        handle_message,
+    )
+}
+
+/// Same as [`run`], but messages are partitioned by `partition_key` instead of classroom.
+/// Messages without a key share a single partition.
+pub fn run_partitioned<C, M, K, P, H, Fut>(
+    nats_client: C,
+    cfg: ConsumerConfig,
+    shutdown_rx: watch::Receiver<()>,
+    partition_key: P,
+    handle_message: H,
+) -> JoinHandle<Result<ShutdownSummary, SubscribeError>>
+where
+    C: NatsClient<M> + 'static,
+    M: ConsumerMessage,
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    P: Fn(&M) -> Option<K> + Send + Sync + 'static,
+    H: Fn(Arc<M>) -> Fut + Send + Sync + 'static,
     Fut: std::future::Future<Output = Result<(), HandleMessageFailure<anyhow::Error>>>
         + std::marker::Send,
 {
     tokio::spawn(async move {
         // In case of subscription errors we don't want to spam sentry
-        let mut log_sentry = LogSentry::new(&cfg);
+        let log_sentry = LogSentry::new(&cfg);
+        let mut shutdown_rx = shutdown_rx;
 
-        loop {
+        let summary = loop {
             let result = nats_client.subscribe_durable().await;
             let messages = match result {
                 Ok(messages) => messages,
                 Err(err) => {
                     log_sentry.log_notify(Error::SubscriptionFailed(err));
 
-                    tokio::time::sleep(cfg.resubscribe_interval).await;
+                    if wait_resubscribe(&cfg, &mut shutdown_rx).await.is_err() {
+                        break ShutdownSummary::default();
+                    }
+                    #[cfg(feature = "metrics")]
+                    metrics::RESUBSCRIBES.inc();
                     continue;
                 }
             };
@@ -118,61 +241,220 @@ where
                 &cfg,
                 messages,
                 shutdown_rx.clone(),
+                &partition_key,
                 &handle_message,
-                &mut log_sentry,
+                &log_sentry,
             )
             .await;
 
             match reason {
-                CompletionReason::Shutdown => {
-                    tracing::warn!("nats consumer completes its work");
-                    break;
+                CompletionReason::Shutdown(summary) => {
+                    tracing::warn!(
+                        completed = summary.completed,
+                        abandoned = summary.abandoned,
+                        nacked = summary.nacked,
+                        "nats consumer completes its work"
+                    );
+                    break summary;
                 }
                 CompletionReason::StreamClosed => {
                     // If the `handle_stream` function ends, then the stream was closed.
                     // Send an error to sentry and try to resubscribe.
                     log_sentry.log_notify(Error::StreamClosed);
-                    tokio::time::sleep(cfg.resubscribe_interval).await;
+                    if wait_resubscribe(&cfg, &mut shutdown_rx).await.is_err() {
+                        break ShutdownSummary::default();
+                    }
+                    #[cfg(feature = "metrics")]
+                    metrics::RESUBSCRIBES.inc();
                     continue;
                 }
             }
-        }
+        };
 
-        Ok::<_, SubscribeError>(())
+        Ok::<_, SubscribeError>(summary)
     })
 }
 
+/// Sleeps before the next subscription attempt. Returns an error on shutdown.
+async fn wait_resubscribe(
+    cfg: &ConsumerConfig,
+    shutdown_rx: &mut watch::Receiver<()>,
+) -> Result<(), ()> {
+    tokio::select! {
+        _ = tokio::time::sleep(cfg.resubscribe_interval) => Ok(()),
+        _ = shutdown_rx.changed() => Err(()),
+    }
+}
+
+/// What happened to the messages the consumer had when it was shut down.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownSummary {
+    /// Handlers that finished before the drain deadline.
+    pub completed: usize,
+    /// Handlers still running at the drain deadline, their messages will be redelivered.
+    pub abandoned: usize,
+    /// Received but not yet handled messages which were NAK'd.
+    pub nacked: usize,
+}
+
+/// Default partition key: the classroom id from the message subject.
+pub fn classroom_id<M: ConsumerMessage>(message: &M) -> Option<Uuid> {
+    Subject::from_str(message.subject())
+        .ok()
+        .map(|subject| subject.classroom_id())
+}
+
 enum CompletionReason {
-    Shutdown,
+    Shutdown(ShutdownSummary),
     StreamClosed,
 }
 
-async fn handle_stream<H, Fut>(
-    nats_client: &Client,
+async fn handle_stream<C, M, K, P, H, Fut>(
+    nats_client: &C,
     cfg: &ConsumerConfig,
-    mut messages: MessageStream,
+    mut messages: MessageStream<M>,
     mut shutdown_rx: watch::Receiver<()>,
+    partition_key: &P,
     handle_message: &H,
-    log_sentry: &mut LogSentry,
+    log_sentry: &LogSentry,
 ) -> CompletionReason
 where
-    H: Fn(Arc<Message>) -> Fut,
+    C: NatsClient<M>,
+    M: ConsumerMessage,
+    K: Eq + Hash + Clone,
+    P: Fn(&M) -> Option<K>,
+    H: Fn(Arc<M>) -> Fut,
     Fut: std::future::Future<Output = Result<(), HandleMessageFailure<anyhow::Error>>>,
 {
+    let concurrency = cfg.concurrency.max(1);
     let mut retry_count = 0;
-    let mut suspend_interval: Option<Duration> = None;
+    let mut suspended_until: Option<Instant> = None;
+
+    // Partitions which have a message in progress or held, with messages waiting for it.
+    let mut partitions: HashMap<Option<K>, VecDeque<Arc<M>>> = HashMap::new();
+    let mut queued = 0;
+    let mut held: HashMap<Option<K>, Hold> = HashMap::new();
+    let mut in_flight = FuturesUnordered::new();
+    let mut stream_closed = false;
+    // Queued messages wait for the running or held ones, keep them from being redelivered meanwhile
+    let interval = cfg.progress_interval.unwrap_or(QUEUED_PROGRESS_INTERVAL);
+    let mut progress = tokio::time::interval_at(Instant::now() + interval, interval);
+    progress.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
     loop {
-        if let Some(interval) = suspend_interval.take() {
-            tracing::warn!(
-                "nats consumer suspenses the processing of nats messages on {} seconds",
-                interval.as_secs()
-            );
-            tokio::time::sleep(interval).await;
+        if stream_closed && in_flight.is_empty() {
+            // Held messages can't come back through this stream, send back the ones waiting for them
+            for message in partitions.into_values().flatten() {
+                nak(message.as_ref(), None, log_sentry).await;
+            }
+            // Stream was closed. Send an error to sentry and try to resubscribe.
+            return CompletionReason::StreamClosed;
         }
 
+        // Messages of held partitions don't count, the held messages must be fetched again
+        let held_queued: usize = held
+            .keys()
+            .filter_map(|key| partitions.get(key))
+            .map(VecDeque::len)
+            .sum();
+        let can_fetch = !stream_closed
+            && suspended_until.is_none()
+            && in_flight.len() < concurrency
+            && queued - held_queued < concurrency;
+        let next_release = held.values().map(|hold| hold.until).min();
+
         tokio::select! {
-            result = messages.next() => {
+            Some((key, outcome)) = in_flight.next(), if !in_flight.is_empty() => {
+                match outcome {
+                    HandleMessageOutcome::Processed => {
+                        retry_count = 0;
+                    }
+                    HandleMessageOutcome::ProcessLater(_) if cfg.redelivery == RedeliveryMode::Delay => {}
+                    HandleMessageOutcome::ProcessLater(_) => {
+                        retry_count += 1;
+                        let interval = next_suspend_interval(retry_count, cfg);
+                        tracing::warn!(
+                            "nats consumer suspenses the processing of nats messages on {:?}",
+                            interval
+                        );
+                        suspended_until = Some(Instant::now() + interval);
+                        #[cfg(feature = "metrics")]
+                        metrics::SUSPENDS.inc();
+                    }
+                    HandleMessageOutcome::WontProcess => {}
+                }
+
+                // The rest of the partition waits for the failed message to come back.
+                // Without a stream sequence it can't be recognized, so the partition goes on.
+                if let HandleMessageOutcome::ProcessLater(Redelivery { stream_sequence: Some(stream_sequence), delay }) = outcome {
+                    let redelivered_at = suspended_until
+                        .unwrap_or_else(Instant::now)
+                        .max(Instant::now() + delay.unwrap_or_default());
+                    let until = redelivered_at + cfg.max_suspend_interval;
+                    held.insert(key, Hold { stream_sequence, until });
+                    continue;
+                }
+
+                // Start the next message of the same partition, if any
+                match partitions.get_mut(&key).and_then(|queue| queue.pop_front()) {
+                    Some(message) => {
+                        queued -= 1;
+                        in_flight.push(process_message(
+                            key,
+                            message,
+                            nats_client,
+                            cfg,
+                            handle_message,
+                            log_sentry,
+                        ));
+                    }
+                    None => {
+                        partitions.remove(&key);
+                    }
+                }
+            }
+            _ = progress.tick(), if queued > 0 => {
+                for message in partitions.values().flatten() {
+                    if let Err(err) = message.ack_with(NatsAckKind::Progress).await {
+                        log_sentry.log_notify(Error::InternalError(
+                            anyhow!(err).context("progress ack failed"),
+                        ));
+                    }
+                }
+            }
+            _ = sleep_until(suspended_until), if suspended_until.is_some() => {
+                suspended_until = None;
+            }
+            // The held message is lost, e.g. it was terminated by the server
+            _ = sleep_until(next_release), if next_release.is_some() => {
+                let now = Instant::now();
+                let expired: Vec<_> = held
+                    .iter()
+                    .filter(|(_, hold)| hold.until <= now)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+
+                for key in expired {
+                    held.remove(&key);
+                    match partitions.get_mut(&key).and_then(|queue| queue.pop_front()) {
+                        Some(message) => {
+                            queued -= 1;
+                            in_flight.push(process_message(
+                                key,
+                                message,
+                                nats_client,
+                                cfg,
+                                handle_message,
+                                log_sentry,
+                            ));
+                        }
+                        None => {
+                            partitions.remove(&key);
+                        }
+                    }
+                }
+            }
+            result = messages.next(), if can_fetch => {
                 let message = match result {
                     Some(Ok(msg)) => msg,
                     Some(Err(err)) => {
@@ -187,68 +469,396 @@ where
                         continue;
                     }
                     None => {
-                        // Stream was closed. Send an error to sentry and try to resubscribe.
-                        return CompletionReason::StreamClosed;
+                        // Finish the messages we already got before resubscribing
+                        stream_closed = true;
+                        continue;
                     }
                 };
                 let message = Arc::new(message);
+                #[cfg(feature = "metrics")]
+                metrics::CONSUMER_MESSAGES.with_label_values(&["consumed"]).inc();
 
                 tracing::info!(
                     "got a message from nats, subject: {:?}, payload: {:?}, headers: {:?}",
-                    message.subject, message.payload, message.headers
+                    message.subject(), String::from_utf8_lossy(message.payload()), message.headers()
                 );
 
-                let outcome = match handle_message(message.clone()).await {
-                    Ok(_) => HandleMessageOutcome::Processed,
-                    Err(HandleMessageFailure::Transient(e)) => {
-                        tracing::error!(%e);
-                        HandleMessageOutcome::ProcessLater
-                    }
-                    Err(HandleMessageFailure::Permanent(e)) => {
-                        log_sentry.log_notify(Error::HandleMessageError(e));
-                        HandleMessageOutcome::WontProcess
+                let key = match std::panic::catch_unwind(AssertUnwindSafe(|| partition_key(&message))) {
+                    Ok(key) => key,
+                    Err(panic) => {
+                        // Process the message in the unkeyed partition rather than losing it
+                        let e = panic_message(panic.as_ref());
+                        log_sentry.log_notify(Error::InternalError(anyhow!(
+                            "partition key panicked: {e}, subject: {}",
+                            message.subject()
+                        )));
+                        None
                     }
                 };
-
-                match outcome {
-                    HandleMessageOutcome::Processed => {
-                        retry_count = 0;
-
-                        if let Err(err) = message.ack().await {
-                            log_sentry.log_notify(Error::InternalError(anyhow!(err).context("ack failed")));
-                        }
+                if let Some(hold) = held.get(&key) {
+                    let is_held = message
+                        .info()
+                        .is_some_and(|info| info.stream_sequence == hold.stream_sequence);
+
+                    if is_held {
+                        // Goes before the messages waiting for it
+                        held.remove(&key);
+                        in_flight.push(process_message(
+                            key,
+                            message,
+                            nats_client,
+                            cfg,
+                            handle_message,
+                            log_sentry,
+                        ));
+                    } else {
+                        partitions.entry(key).or_default().push_back(message);
+                        queued += 1;
                     }
-                    HandleMessageOutcome::ProcessLater => {
-                        if let Err(err) = message.ack_with(NatsAckKind::Nak(None)).await {
-                            log_sentry.log_notify(Error::InternalError(anyhow!(err).context("nack failed")));
-                        }
+                    continue;
+                }
 
-                        retry_count += 1;
-                        let interval = next_suspend_interval(retry_count, cfg);
-                        suspend_interval = Some(interval);
+                match partitions.get_mut(&key) {
+                    Some(queue) => {
+                        queue.push_back(message);
+                        queued += 1;
                     }
-                    HandleMessageOutcome::WontProcess => {
-                        if let Err(err) = nats_client.terminate(&message).await {
-                            log_sentry.log_notify(Error::InternalError(anyhow!(err).context("failed to terminate msg")));
-                        }
+                    None => {
+                        partitions.insert(key.clone(), VecDeque::new());
+                        in_flight.push(process_message(
+                            key,
+                            message,
+                            nats_client,
+                            cfg,
+                            handle_message,
+                            log_sentry,
+                        ));
                     }
                 }
             }
             // Graceful shutdown
             _ = shutdown_rx.changed() => {
-                return CompletionReason::Shutdown;
+                let summary = drain(cfg, messages, &mut in_flight, partitions, log_sentry).await;
+                return CompletionReason::Shutdown(summary);
             }
         }
     }
 }
 
-fn next_suspend_interval(retry_count: u32, nats_consumer_config: &ConsumerConfig) -> Duration {
-    let seconds = std::cmp::min(
-        nats_consumer_config.suspend_interval.as_secs() * 2_u64.pow(retry_count),
-        nats_consumer_config.max_suspend_interval.as_secs(),
+/// Stops fetching, NAKs the messages which weren't handled yet and lets the handlers
+/// that are already running finish their work until `cfg.drain_timeout`.
+async fn drain<M, K, F>(
+    cfg: &ConsumerConfig,
+    mut messages: MessageStream<M>,
+    in_flight: &mut FuturesUnordered<F>,
+    partitions: HashMap<Option<K>, VecDeque<Arc<M>>>,
+    log_sentry: &LogSentry,
+) -> ShutdownSummary
+where
+    M: ConsumerMessage,
+    F: std::future::Future,
+{
+    let deadline = Instant::now() + cfg.drain_timeout;
+    let mut completed = 0;
+
+    let nack_rest = async {
+        let mut nacked = 0;
+        let mut sequences = HashSet::new();
+
+        for message in partitions.into_values().flatten() {
+            if let Some(info) = message.info() {
+                sequences.insert(info.stream_sequence);
+            }
+            if send_back(message.as_ref(), log_sentry).await {
+                nacked += 1;
+            }
+        }
+
+        // Reading the stream may send a new fetch request. Keep reading until it's quiet,
+        // so the fetched messages are sent back too instead of waiting out `ack_wait`.
+        loop {
+            let quiet = std::cmp::min(Instant::now() + DRAIN_QUIET_PERIOD, deadline);
+            let message = match tokio::time::timeout_at(quiet, messages.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(_))) => continue,
+                Ok(None) | Err(_) => break,
+            };
+
+            // A NAKed message came back, so there is nothing new left to fetch
+            let is_returned = message
+                .info()
+                .is_some_and(|info| !sequences.insert(info.stream_sequence));
+            if send_back(&message, log_sentry).await {
+                nacked += 1;
+            }
+            if is_returned {
+                break;
+            }
+        }
+        drop(messages);
+
+        nacked
+    };
+
+    let finish = async {
+        let finish = async {
+            while in_flight.next().await.is_some() {
+                completed += 1;
+            }
+        };
+        if tokio::time::timeout_at(deadline, finish).await.is_err() {
+            tracing::warn!("nats consumer drain deadline passed");
+        }
+    };
+
+    let (nacked, ()) = tokio::join!(nack_rest, finish);
+
+    ShutdownSummary {
+        completed,
+        abandoned: in_flight.len(),
+        nacked,
+    }
+}
+
+/// NAKs a message which wasn't handled during the drain, returns whether it succeeded.
+async fn send_back<M: ConsumerMessage>(message: &M, log_sentry: &LogSentry) -> bool {
+    match message.ack_with(NatsAckKind::Nak(None)).await {
+        Ok(_) => true,
+        Err(err) => {
+            log_sentry.log_notify(Error::InternalError(anyhow!(err).context("nack failed")));
+            false
+        }
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn process_message<C, M, K, H, Fut>(
+    key: Option<K>,
+    message: Arc<M>,
+    nats_client: &C,
+    cfg: &ConsumerConfig,
+    handle_message: &H,
+    log_sentry: &LogSentry,
+) -> (Option<K>, HandleMessageOutcome)
+where
+    C: NatsClient<M>,
+    M: ConsumerMessage,
+    H: Fn(Arc<M>) -> Fut,
+    Fut: std::future::Future<Output = Result<(), HandleMessageFailure<anyhow::Error>>>,
+{
+    #[cfg(feature = "metrics")]
+    let started = Instant::now();
+
+    let span = tracing::info_span!(
+        "nats_message",
+        subject = message.subject(),
+        stream_sequence = tracing::field::Empty,
+        delivered = tracing::field::Empty,
     );
+    if let Some(info) = message.info() {
+        span.record("stream_sequence", info.stream_sequence);
+        span.record("delivered", info.delivered);
+    }
+    #[cfg(feature = "opentelemetry")]
+    if let Some(headers) = message.headers() {
+        crate::trace_context::set_parent(&span, headers);
+    }
+
+    let handler = async {
+        // The handler is called inside the future, so a panic before its first
+        // await point is caught as well
+        match AssertUnwindSafe(async { handle_message(message.clone()).await })
+            .catch_unwind()
+            .await
+        {
+            Ok(result) => result,
+            Err(panic) => Err(panic_failure(panic, message.as_ref(), cfg, log_sentry)),
+        }
+    }
+    .instrument(span);
+    let result = run_handler(message.as_ref(), handler, cfg, log_sentry).await;
+
+    #[cfg(feature = "metrics")]
+    metrics::HANDLER_DURATION
+        .with_label_values(&[match &result {
+            Ok(_) => "ok",
+            Err(HandleMessageFailure::Transient(_)) => "transient",
+            Err(HandleMessageFailure::Permanent(_)) => "permanent",
+        }])
+        .observe(started.elapsed().as_secs_f64());
+
+    let mut reason = None;
+    let outcome = match result {
+        Ok(_) => HandleMessageOutcome::Processed,
+        Err(HandleMessageFailure::Transient(e)) if is_last_attempt(message.as_ref(), cfg) => {
+            reason = Some(format!("max delivery attempts reached: {e:#}"));
+            log_sentry.log_notify(Error::MaxAttemptsReached(e));
+            HandleMessageOutcome::WontProcess
+        }
+        Err(HandleMessageFailure::Transient(e)) => {
+            tracing::error!(%e);
+            let info = message.info();
+            let delay = match cfg.redelivery {
+                RedeliveryMode::Suspend => None,
+                RedeliveryMode::Delay => {
+                    let delivered = info.as_ref().map(|info| info.delivered).unwrap_or(1);
+                    Some(next_suspend_interval(delivered.max(1) as u32, cfg))
+                }
+            };
+            HandleMessageOutcome::ProcessLater(Redelivery {
+                stream_sequence: info.map(|info| info.stream_sequence),
+                delay,
+            })
+        }
+        Err(HandleMessageFailure::Permanent(e)) => {
+            reason = Some(format!("{e:#}"));
+            log_sentry.log_notify(Error::HandleMessageError(e));
+            HandleMessageOutcome::WontProcess
+        }
+    };
+
+    match &outcome {
+        HandleMessageOutcome::Processed => {
+            if let Err(err) = message.ack().await {
+                log_sentry.log_notify(Error::InternalError(anyhow!(err).context("ack failed")));
+            }
 
-    Duration::from_secs(seconds)
+            #[cfg(feature = "metrics")]
+            metrics::CONSUMER_MESSAGES
+                .with_label_values(&["acked"])
+                .inc();
+        }
+        HandleMessageOutcome::ProcessLater(redelivery) => {
+            nak(message.as_ref(), redelivery.delay, log_sentry).await;
+        }
+        HandleMessageOutcome::WontProcess => {
+            let reason = reason.unwrap_or_default();
+            if let Err(err) = nats_client.terminate(&message, &reason).await {
+                log_sentry.log_notify(Error::InternalError(
+                    anyhow!(err).context("failed to terminate msg"),
+                ));
+            }
+
+            #[cfg(feature = "metrics")]
+            metrics::CONSUMER_MESSAGES
+                .with_label_values(&["terminated"])
+                .inc();
+        }
+    }
+
+    (key, outcome)
+}
+
+async fn nak<M: ConsumerMessage>(message: &M, delay: Option<Duration>, log_sentry: &LogSentry) {
+    if let Err(err) = message.ack_with(NatsAckKind::Nak(delay)).await {
+        log_sentry.log_notify(Error::InternalError(anyhow!(err).context("nack failed")));
+    }
+
+    #[cfg(feature = "metrics")]
+    metrics::CONSUMER_MESSAGES
+        .with_label_values(&["nacked"])
+        .inc();
+}
+
+/// Runs the handler, sending progress acks and enforcing the timeout if configured.
+async fn run_handler<M, Fut>(
+    message: &M,
+    handler: Fut,
+    cfg: &ConsumerConfig,
+    log_sentry: &LogSentry,
+) -> HandleResult
+where
+    M: ConsumerMessage,
+    Fut: std::future::Future<Output = HandleResult>,
+{
+    tokio::pin!(handler);
+
+    let deadline = cfg.handler_timeout.map(|timeout| Instant::now() + timeout);
+    let mut progress = cfg
+        .progress_interval
+        .map(|interval| tokio::time::interval_at(Instant::now() + interval, interval));
+
+    loop {
+        tokio::select! {
+            result = &mut handler => return result,
+            _ = sleep_until(deadline) => {
+                return Err(HandleMessageFailure::Transient(anyhow!(
+                    "handler timed out after {:?}",
+                    cfg.handler_timeout.unwrap_or_default()
+                )));
+            }
+            _ = tick(progress.as_mut()) => {
+                if let Err(err) = message.ack_with(NatsAckKind::Progress).await {
+                    log_sentry.log_notify(Error::InternalError(
+                        anyhow!(err).context("progress ack failed"),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+async fn tick(interval: Option<&mut tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+fn panic_failure<M: ConsumerMessage>(
+    panic: Box<dyn Any + Send>,
+    message: &M,
+    cfg: &ConsumerConfig,
+    log_sentry: &LogSentry,
+) -> HandleMessageFailure<anyhow::Error> {
+    let panic = panic_message(panic.as_ref());
+    let subject = message.subject();
+    let e = anyhow!("handler panicked: {panic}, subject: {subject}");
+
+    match cfg.panic_failure {
+        PanicFailure::Transient => {
+            // Permanent failures and the last attempt are reported by the caller
+            if !is_last_attempt(message, cfg) {
+                log_sentry.log_notify(Error::HandlerPanicked(anyhow!(
+                    "{panic}, subject: {subject}"
+                )));
+            }
+            HandleMessageFailure::Transient(e)
+        }
+        PanicFailure::Permanent => HandleMessageFailure::Permanent(e),
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+fn is_last_attempt<M: ConsumerMessage>(message: &M, cfg: &ConsumerConfig) -> bool {
+    match (cfg.max_attempts, message.info()) {
+        (Some(max_attempts), Some(info)) => info.delivered >= i64::from(max_attempts),
+        _ => false,
+    }
+}
+
+fn next_suspend_interval(retry_count: u32, nats_consumer_config: &ConsumerConfig) -> Duration {
+    std::cmp::min(
+        nats_consumer_config
+            .suspend_interval
+            .saturating_mul(2_u32.saturating_pow(retry_count)),
+        nats_consumer_config.max_suspend_interval,
+    )
 }
 
 fn notify_sentry(e: Error) {
@@ -258,7 +868,7 @@ fn notify_sentry(e: Error) {
 }
 
 struct LogSentry {
-    sentry_last_sent: Instant,
+    sentry_last_sent: Mutex<Instant>,
     suspend_interval: Duration,
 }
 
@@ -266,17 +876,25 @@ impl LogSentry {
     pub fn new(cfg: &ConsumerConfig) -> Self {
         let sentry_last_sent = Instant::now() - cfg.suspend_sentry_interval * 2;
         Self {
-            sentry_last_sent,
+            sentry_last_sent: Mutex::new(sentry_last_sent),
             suspend_interval: cfg.suspend_interval,
         }
     }
 
-    pub fn log_notify(&mut self, e: Error) {
+    pub fn log_notify(&self, e: Error) {
         tracing::error!(%e);
 
-        if self.sentry_last_sent.elapsed() >= self.suspend_interval {
+        let mut sentry_last_sent = self
+            .sentry_last_sent
+            .lock()
+            .expect("failed to get lock on sentry_last_sent");
+
+        if sentry_last_sent.elapsed() >= self.suspend_interval {
             notify_sentry(e);
-            self.sentry_last_sent = Instant::now();
+            *sentry_last_sent = Instant::now();
+        } else {
+            #[cfg(feature = "metrics")]
+            metrics::SENTRY_THROTTLED.inc();
         }
     }
 }
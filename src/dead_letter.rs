@@ -0,0 +1,204 @@
+use std::time::SystemTime;
+
+use async_nats::{header::NATS_MESSAGE_ID, HeaderMap};
+
+use crate::{
+    headers,
+    subject::{Subject, SubjectError},
+    ConsumerMessage,
+};
+
+const HEADER_PREFIX: &str = "Dead-Letter-";
+const ORIGINAL_SUBJECT: &str = "Dead-Letter-Original-Subject";
+const ERROR: &str = "Dead-Letter-Error";
+const DELIVERED: &str = "Dead-Letter-Delivered";
+const STREAM: &str = "Dead-Letter-Stream";
+const STREAM_SEQUENCE: &str = "Dead-Letter-Stream-Sequence";
+const FAILED_AT: &str = "Dead-Letter-Failed-At";
+const MESSAGE_ID: &str = "Dead-Letter-Msg-Id";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeadLetterError {
+    #[error("failed to get `{0}`")]
+    InvalidHeader(String),
+    #[error("failed to parse `{0}`")]
+    InvalidNumber(String, #[source] std::num::ParseIntError),
+    #[error("failed to parse failure time")]
+    InvalidFailedAt(#[from] humantime::TimestampError),
+}
+
+/// Failure metadata attached to a message published to the dead-letter subject.
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    original_subject: String,
+    error: String,
+    delivered: Option<i64>,
+    stream: Option<String>,
+    stream_sequence: Option<u64>,
+    failed_at: SystemTime,
+}
+
+impl DeadLetter {
+    pub fn new<M: ConsumerMessage>(message: &M, error: &str) -> Self {
+        let info = message.info();
+
+        Self {
+            original_subject: message.subject().to_owned(),
+            // Header values can't contain line breaks
+            error: error.replace(['\r', '\n'], " "),
+            delivered: info.as_ref().map(|info| info.delivered),
+            stream: info.as_ref().map(|info| info.stream.clone()),
+            stream_sequence: info.as_ref().map(|info| info.stream_sequence),
+            failed_at: SystemTime::now(),
+        }
+    }
+
+    /// Reads the failure metadata from the headers of a dead-lettered message.
+    pub fn from_message<M: ConsumerMessage>(message: &M) -> Result<Self, DeadLetterError> {
+        match message.headers() {
+            Some(headers) => Self::try_from(headers),
+            None => Err(DeadLetterError::InvalidHeader(ORIGINAL_SUBJECT.to_string())),
+        }
+    }
+
+    pub fn original_subject(&self) -> &str {
+        &self.original_subject
+    }
+
+    pub fn subject(&self) -> Result<Subject, SubjectError> {
+        self.original_subject.parse()
+    }
+
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+
+    pub fn delivered(&self) -> Option<i64> {
+        self.delivered
+    }
+
+    pub fn stream(&self) -> Option<&str> {
+        self.stream.as_deref()
+    }
+
+    pub fn stream_sequence(&self) -> Option<u64> {
+        self.stream_sequence
+    }
+
+    pub fn failed_at(&self) -> SystemTime {
+        self.failed_at
+    }
+
+    /// Adds the failure metadata to the headers of the message.
+    pub fn insert_into(&self, headers: &mut HeaderMap) {
+        headers.insert(ORIGINAL_SUBJECT, self.original_subject.as_str());
+        headers.insert(ERROR, self.error.as_str());
+        headers.insert(
+            FAILED_AT,
+            humantime::format_rfc3339_millis(self.failed_at)
+                .to_string()
+                .as_str(),
+        );
+
+        if let Some(delivered) = self.delivered {
+            headers.insert(DELIVERED, delivered.to_string().as_str());
+        }
+
+        if let Some(stream) = &self.stream {
+            headers.insert(STREAM, stream.as_str());
+        }
+
+        if let Some(stream_sequence) = self.stream_sequence {
+            headers.insert(STREAM_SEQUENCE, stream_sequence.to_string().as_str());
+        }
+    }
+}
+
+impl TryFrom<&HeaderMap> for DeadLetter {
+    type Error = DeadLetterError;
+
+    fn try_from(value: &HeaderMap) -> Result<Self, Self::Error> {
+        let original_subject = value
+            .get(ORIGINAL_SUBJECT)
+            .ok_or(DeadLetterError::InvalidHeader(ORIGINAL_SUBJECT.to_string()))?
+            .to_string();
+
+        let error = value
+            .get(ERROR)
+            .ok_or(DeadLetterError::InvalidHeader(ERROR.to_string()))?
+            .to_string();
+
+        let failed_at = value
+            .get(FAILED_AT)
+            .ok_or(DeadLetterError::InvalidHeader(FAILED_AT.to_string()))?
+            .as_str();
+        let failed_at = humantime::parse_rfc3339(failed_at)?;
+
+        let delivered = value
+            .get(DELIVERED)
+            .map(|h| {
+                h.as_str()
+                    .parse::<i64>()
+                    .map_err(|e| DeadLetterError::InvalidNumber(DELIVERED.to_string(), e))
+            })
+            .transpose()?;
+
+        let stream = value.get(STREAM).map(|h| h.to_string());
+
+        let stream_sequence = value
+            .get(STREAM_SEQUENCE)
+            .map(|h| {
+                h.as_str()
+                    .parse::<u64>()
+                    .map_err(|e| DeadLetterError::InvalidNumber(STREAM_SEQUENCE.to_string(), e))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            original_subject,
+            error,
+            delivered,
+            stream,
+            stream_sequence,
+            failed_at,
+        })
+    }
+}
+
+/// Returns the headers to publish a failed message to the dead-letter subject with.
+/// The message id is kept in a `Dead-Letter-` header, otherwise the dead letter
+/// would be dropped as a duplicate of the original message.
+pub(crate) fn dead_letter_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut dead_letter = HeaderMap::new();
+
+    for (name, value) in headers::without_expectations(headers).iter() {
+        for value in value.iter() {
+            if *name == NATS_MESSAGE_ID {
+                dead_letter.append(MESSAGE_ID, value);
+            } else {
+                dead_letter.append(name.clone(), value);
+            }
+        }
+    }
+
+    dead_letter
+}
+
+/// Returns the headers of a dead-lettered message without the failure metadata
+/// and with the original message id.
+pub fn original_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut original = HeaderMap::new();
+
+    for (name, value) in headers::without_expectations(headers).iter() {
+        let name_str: &str = name.as_ref();
+        for value in value.iter() {
+            if name_str == MESSAGE_ID {
+                original.append(NATS_MESSAGE_ID, value);
+            } else if !name_str.starts_with(HEADER_PREFIX) {
+                original.append(name.clone(), value);
+            }
+        }
+    }
+
+    original
+}
@@ -1,6 +1,9 @@
+use std::str::FromStr;
+
 use crate::{
-    headers::{Builder as HeadersBuilder, Headers},
-    subject::Subject,
+    headers::{Builder as HeadersBuilder, HeaderError, Headers, PublishExpectations},
+    subject::{Subject, SubjectError},
+    CoreMessage,
 };
 use svc_agent::AgentId;
 use svc_events::EventId;
@@ -26,6 +29,36 @@ impl Event {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum EventError {
+    #[error(transparent)]
+    InvalidSubject(#[from] SubjectError),
+    #[error("headers are not found")]
+    HeadersNotFound,
+    #[error(transparent)]
+    InvalidHeaders(#[from] HeaderError),
+}
+
+/// Reads the event envelope from a message received with `CoreNatsClient::subscribe_core`.
+impl TryFrom<&CoreMessage> for Event {
+    type Error = EventError;
+
+    fn try_from(message: &CoreMessage) -> Result<Self, Self::Error> {
+        let subject = Subject::from_str(&message.subject)?;
+        let headers = message
+            .headers
+            .clone()
+            .ok_or(EventError::HeadersNotFound)?
+            .try_into()?;
+
+        Ok(Self {
+            subject,
+            payload: message.payload.to_vec(),
+            headers,
+        })
+    }
+}
+
 pub struct Builder {
     subject: Subject,
     payload: Vec<u8>,
@@ -34,6 +67,8 @@ pub struct Builder {
     is_internal: bool,
     receiver_id: Option<AgentId>,
     is_deduplication_enabled: bool,
+    trace_context: Option<(String, Option<String>)>,
+    expectations: PublishExpectations,
 }
 
 impl Builder {
@@ -46,6 +81,8 @@ impl Builder {
             is_internal: true,
             receiver_id: None,
             is_deduplication_enabled: true,
+            trace_context: None,
+            expectations: PublishExpectations::default(),
         }
     }
 
@@ -70,8 +107,53 @@ impl Builder {
         }
     }
 
+    /// Sets W3C `traceparent`/`tracestate` headers. With the `opentelemetry` feature
+    /// they are taken from the current span by default.
+    pub fn trace_context(self, traceparent: String, tracestate: Option<String>) -> Self {
+        Self {
+            trace_context: Some((traceparent, tracestate)),
+            ..self
+        }
+    }
+
+    /// Publish only if the last sequence of the stream matches, otherwise
+    /// the publish fails with `PublishError::WrongLastSequence`.
+    pub fn expect_last_sequence(self, sequence: u64) -> Self {
+        Self {
+            expectations: PublishExpectations {
+                last_sequence: Some(sequence),
+                ..self.expectations
+            },
+            ..self
+        }
+    }
+
+    /// Publish only if the sequence of the last message on the event's subject matches,
+    /// `0` means there must be no messages on the subject.
+    pub fn expect_last_subject_sequence(self, sequence: u64) -> Self {
+        Self {
+            expectations: PublishExpectations {
+                last_subject_sequence: Some(sequence),
+                ..self.expectations
+            },
+            ..self
+        }
+    }
+
+    /// Publish only if the subject is bound to the given stream.
+    pub fn expect_stream(self, stream: String) -> Self {
+        Self {
+            expectations: PublishExpectations {
+                stream: Some(stream),
+                ..self.expectations
+            },
+            ..self
+        }
+    }
+
     pub fn build(self) -> Event {
         let mut builder = HeadersBuilder::new(self.event_id, self.sender_id)
+            .expectations(self.expectations)
             .internal(self.is_internal)
             .enable_deduplication(self.is_deduplication_enabled);
 
@@ -79,6 +161,10 @@ impl Builder {
             builder = builder.receiver_id(receiver_id);
         }
 
+        if let Some((traceparent, tracestate)) = self.trace_context {
+            builder = builder.trace_context(traceparent, tracestate);
+        }
+
         let headers = builder.build();
 
         Event {
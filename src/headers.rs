@@ -4,10 +4,13 @@ use svc_events::EventId;
 
 const SENDER_ID: &str = "Sender-Agent-Id";
 const ENTITY_EVENT_SEQUENCE_ID: &str = "Entity-Event-Sequence-Id";
-const ENTITY_EVENT_TYPE: &str = "Entity-Event-Type";
-const ENTITY_EVENT_OPERATION: &str = "Entity-Event-Operation";
+pub(crate) const ENTITY_EVENT_TYPE: &str = "Entity-Event-Type";
+pub(crate) const ENTITY_EVENT_OPERATION: &str = "Entity-Event-Operation";
 const IS_INTERNAL: &str = "Is-Internal";
 const RECEIVER_ID: &str = "Receiver-Agent-Id";
+pub(crate) const TRACEPARENT: &str = "traceparent";
+pub(crate) const TRACESTATE: &str = "tracestate";
+const EXPECTED_PREFIX: &str = "Nats-Expected-";
 
 #[derive(Debug, thiserror::Error)]
 pub enum HeaderError {
@@ -21,6 +24,15 @@ pub enum HeaderError {
     InvalidIsInternal(#[from] std::str::ParseBoolError),
 }
 
+/// Conditions checked by JetStream before storing the event. They are only sent
+/// on publish and are not read back from consumed messages.
+#[derive(Debug, Clone, Default)]
+pub struct PublishExpectations {
+    pub last_sequence: Option<u64>,
+    pub last_subject_sequence: Option<u64>,
+    pub stream: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Headers {
     event_id: EventId,
@@ -28,6 +40,9 @@ pub struct Headers {
     is_internal: bool,
     receiver_id: Option<AgentId>,
     is_deduplication_enabled: bool,
+    traceparent: Option<String>,
+    tracestate: Option<String>,
+    expectations: PublishExpectations,
 }
 
 impl Headers {
@@ -46,6 +61,19 @@ impl Headers {
     pub fn receiver_id(&self) -> Option<&AgentId> {
         self.receiver_id.as_ref()
     }
+
+    /// W3C trace context of the span that produced the event.
+    pub fn traceparent(&self) -> Option<&str> {
+        self.traceparent.as_deref()
+    }
+
+    pub fn tracestate(&self) -> Option<&str> {
+        self.tracestate.as_deref()
+    }
+
+    pub fn expectations(&self) -> &PublishExpectations {
+        &self.expectations
+    }
 }
 
 pub struct Builder {
@@ -54,6 +82,9 @@ pub struct Builder {
     is_internal: bool,
     receiver_id: Option<AgentId>,
     is_deduplication_enabled: bool,
+    traceparent: Option<String>,
+    tracestate: Option<String>,
+    expectations: PublishExpectations,
 }
 
 impl Builder {
@@ -64,6 +95,9 @@ impl Builder {
             is_internal: true,
             receiver_id: None,
             is_deduplication_enabled: true,
+            traceparent: None,
+            tracestate: None,
+            expectations: PublishExpectations::default(),
         }
     }
 
@@ -88,13 +122,41 @@ impl Builder {
         }
     }
 
+    pub fn trace_context(self, traceparent: String, tracestate: Option<String>) -> Self {
+        Self {
+            traceparent: Some(traceparent),
+            tracestate,
+            ..self
+        }
+    }
+
+    pub fn expectations(self, expectations: PublishExpectations) -> Self {
+        Self {
+            expectations,
+            ..self
+        }
+    }
+
+    /// With the `opentelemetry` feature the trace context of the current span
+    /// is used unless it was set explicitly.
     pub fn build(self) -> Headers {
+        #[cfg(feature = "opentelemetry")]
+        let (traceparent, tracestate) = match self.traceparent {
+            Some(traceparent) => (Some(traceparent), self.tracestate),
+            None => crate::trace_context::current(),
+        };
+        #[cfg(not(feature = "opentelemetry"))]
+        let (traceparent, tracestate) = (self.traceparent, self.tracestate);
+
         Headers {
             event_id: self.event_id,
             sender_id: self.sender_id,
             is_internal: self.is_internal,
             receiver_id: self.receiver_id,
             is_deduplication_enabled: self.is_deduplication_enabled,
+            traceparent,
+            tracestate,
+            expectations: self.expectations,
         }
     }
 }
@@ -125,6 +187,31 @@ impl From<Headers> for async_nats::HeaderMap {
             headers.insert(RECEIVER_ID, receiver_id.to_string().as_str());
         }
 
+        if let Some(traceparent) = value.traceparent() {
+            headers.insert(TRACEPARENT, traceparent);
+        }
+
+        if let Some(tracestate) = value.tracestate() {
+            headers.insert(TRACESTATE, tracestate);
+        }
+
+        let expectations = value.expectations();
+        if let Some(sequence) = expectations.last_sequence {
+            headers.insert(
+                async_nats::header::NATS_EXPECTED_LAST_SEQUENCE,
+                sequence.to_string().as_str(),
+            );
+        }
+        if let Some(sequence) = expectations.last_subject_sequence {
+            headers.insert(
+                async_nats::header::NATS_EXPECTED_LAST_SUBJECT_SEQUENCE,
+                sequence.to_string().as_str(),
+            );
+        }
+        if let Some(stream) = &expectations.stream {
+            headers.insert(async_nats::header::NATS_EXPECTED_STREAM, stream.as_str());
+        }
+
         headers
     }
 }
@@ -174,12 +261,36 @@ impl TryFrom<async_nats::HeaderMap> for Headers {
 
         let is_deduplication_enabled = value.get(async_nats::header::NATS_MESSAGE_ID).is_some();
 
+        let traceparent = value.get(TRACEPARENT).map(|h| h.to_string());
+        let tracestate = value.get(TRACESTATE).map(|h| h.to_string());
+
         Ok(Self {
             event_id,
             sender_id,
             is_internal,
             receiver_id,
             is_deduplication_enabled,
+            traceparent,
+            tracestate,
+            expectations: PublishExpectations::default(),
         })
     }
 }
+
+/// Returns the headers without the publish expectations, so a stored message
+/// can be published again to another subject.
+pub(crate) fn without_expectations(headers: &async_nats::HeaderMap) -> async_nats::HeaderMap {
+    let mut result = async_nats::HeaderMap::new();
+
+    for (name, value) in headers.iter() {
+        if name.to_string().starts_with(EXPECTED_PREFIX) {
+            continue;
+        }
+
+        for value in value.iter() {
+            result.append(name.clone(), value);
+        }
+    }
+
+    result
+}
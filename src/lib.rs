@@ -1,45 +1,197 @@
-use async_nats::jetstream::consumer::pull::{MessagesError, Stream};
+use async_nats::{
+    jetstream::consumer::pull::{MessagesError, Stream},
+    HeaderMap,
+};
+use bytes::Bytes;
 use std::{
     pin::Pin,
     task::{Context, Poll},
+    time::{Duration, SystemTime},
 };
 
 pub use crate::{
-    client::{Client, PublishError, SubscribeError, TermMessageError},
-    config::{Config, ConsumerConfig},
+    client::{
+        Client, ConnectError, ConnectionEvent, Health, HealthError, PublishError, RequestError,
+        SubscribeError, TermMessageError,
+    },
+    config::{
+        Auth, Config, ConnectionConfig, ConsumerConfig, DeadLetterConfig, PanicFailure,
+        ProvisionConfig, PullConsumerConfig, ReconnectDelay, RedeliveryMode, StreamConfig,
+        TlsConfig,
+    },
+    dead_letter::DeadLetter,
     event::Event,
-    headers::Headers,
+    headers::{Headers, PublishExpectations},
     subject::Subject,
 };
 pub use async_nats::jetstream::{
     consumer::{push::Messages, AckPolicy, DeliverPolicy},
+    publish::PublishAck,
     AckKind, Message,
 };
+pub use async_nats::{connection::State as ConnectionState, Message as CoreMessage, Subscriber};
 
 pub mod consumer;
+pub mod dead_letter;
 pub mod event;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod middleware;
+pub mod provision;
+pub mod redrive;
+pub mod router;
+pub mod rpc;
+pub mod supervisor;
 pub mod test_helpers;
+pub mod typed;
 
 mod client;
 mod config;
 mod headers;
 mod subject;
+mod tls;
+#[cfg(feature = "opentelemetry")]
+mod trace_context;
+
+type BoxedStream<M> = Pin<Box<dyn futures::Stream<Item = Result<M, MessagesError>> + Send>>;
+
+pub struct MessageStream<M = Message>(BoxedStream<M>);
+
+impl<M> MessageStream<M> {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: futures::Stream<Item = Result<M, MessagesError>> + Send + 'static,
+    {
+        Self(Box::pin(stream))
+    }
+}
 
-pub struct MessageStream(Stream);
+impl From<Stream> for MessageStream {
+    fn from(stream: Stream) -> Self {
+        Self::new(stream)
+    }
+}
 
-impl futures::Stream for MessageStream {
-    type Item = Result<Message, MessagesError>;
+impl<M> futures::Stream for MessageStream<M> {
+    type Item = Result<M, MessagesError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.0).poll_next(cx)
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+/// Messages of a core NATS subscription, dropping it unsubscribes.
+pub struct CoreMessageStream(Pin<Box<dyn futures::Stream<Item = CoreMessage> + Send>>);
+
+impl CoreMessageStream {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: futures::Stream<Item = CoreMessage> + Send + 'static,
+    {
+        Self(Box::pin(stream))
+    }
+}
+
+impl From<Subscriber> for CoreMessageStream {
+    fn from(subscriber: Subscriber) -> Self {
+        Self::new(subscriber)
+    }
+}
+
+impl futures::Stream for CoreMessageStream {
+    type Item = CoreMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+/// JetStream metadata of a consumed message.
+#[derive(Debug, Clone)]
+pub struct MessageInfo {
+    pub stream: String,
+    pub consumer: String,
+    pub stream_sequence: u64,
+    pub consumer_sequence: u64,
+    /// Number of times the message was delivered, starting from 1.
+    pub delivered: i64,
+    pub published: SystemTime,
+}
+
+/// A message received from a durable consumer which can be acknowledged.
+#[async_trait::async_trait]
+pub trait ConsumerMessage: Send + Sync + 'static {
+    fn subject(&self) -> &str;
+
+    fn payload(&self) -> &[u8];
+
+    fn headers(&self) -> Option<&HeaderMap>;
+
+    /// Returns `None` if the message doesn't carry JetStream metadata.
+    fn info(&self) -> Option<MessageInfo>;
+
+    async fn ack(&self) -> Result<(), async_nats::Error>;
+
+    async fn ack_with(&self, kind: AckKind) -> Result<(), async_nats::Error>;
+}
+
+#[async_trait::async_trait]
+impl ConsumerMessage for Message {
+    fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    fn headers(&self) -> Option<&HeaderMap> {
+        self.headers.as_ref()
+    }
+
+    fn info(&self) -> Option<MessageInfo> {
+        let info = Message::info(self).ok()?;
+
+        Some(MessageInfo {
+            stream: info.stream.to_owned(),
+            consumer: info.consumer.to_owned(),
+            stream_sequence: info.stream_sequence,
+            consumer_sequence: info.consumer_sequence,
+            delivered: info.delivered,
+            published: info.published.into(),
+        })
+    }
+
+    async fn ack(&self) -> Result<(), async_nats::Error> {
+        Message::ack(self).await
+    }
+
+    async fn ack_with(&self, kind: AckKind) -> Result<(), async_nats::Error> {
+        Message::ack_with(self, kind).await
     }
 }
 
 #[async_trait::async_trait]
-pub trait NatsClient: Send + Sync {
-    async fn publish(&self, event: &Event) -> Result<(), PublishError>;
+pub trait NatsClient<M = Message>: Send + Sync {
+    /// Publishes the event to JetStream and waits for the ack. Check `PublishAck::duplicate`
+    /// to find out whether the server dropped the event by its `Nats-Msg-Id`.
+    async fn publish(&self, event: &Event) -> Result<PublishAck, PublishError>;
 
-    async fn subscribe_durable(&self) -> Result<MessageStream, SubscribeError>;
+    /// Publishes the events and returns the results in the same order.
+    /// Implementations may keep up to `max_pending` acks in flight.
+    async fn publish_batch(
+        &self,
+        events: &[Event],
+        _max_pending: usize,
+    ) -> Vec<Result<PublishAck, PublishError>> {
+        let mut results = Vec::with_capacity(events.len());
+        for event in events {
+            results.push(self.publish(event).await);
+        }
+        results
+    }
+
+    async fn subscribe_durable(&self) -> Result<MessageStream<M>, SubscribeError>;
 
     async fn subscribe_ephemeral(
         &self,
@@ -48,5 +200,61 @@ pub trait NatsClient: Send + Sync {
         ack_policy: AckPolicy,
     ) -> Result<Messages, SubscribeError>;
 
-    async fn terminate(&self, message: &Message) -> Result<(), TermMessageError>;
+    /// Stops redelivery of the message and publishes it to the dead-letter subject
+    /// along with the `reason` of the failure.
+    async fn terminate(&self, message: &M, reason: &str) -> Result<(), TermMessageError>;
+}
+
+/// Core NATS messaging, without JetStream persistence.
+#[async_trait::async_trait]
+pub trait CoreNatsClient: Send + Sync {
+    /// Publishes the event with core NATS, without waiting for a JetStream ack.
+    async fn publish_core(&self, event: &Event) -> Result<(), PublishError>;
+
+    /// Subscribes with core NATS, the subject may contain wildcards.
+    /// Subscribers in the same queue group share the messages.
+    async fn subscribe_core(
+        &self,
+        subject: &str,
+        queue_group: Option<&str>,
+    ) -> Result<CoreMessageStream, SubscribeError>;
+
+    /// Sends the event as a core NATS request and waits for the response.
+    async fn request(&self, event: &Event, timeout: Duration) -> Result<CoreMessage, RequestError> {
+        self.request_with_headers(
+            event.subject().to_string(),
+            event.headers().to_owned().into(),
+            event.payload().to_owned().into(),
+            timeout,
+        )
+        .await
+    }
+
+    /// Sends a request to any subject, e.g. the address of an RPC server.
+    async fn request_with_headers(
+        &self,
+        subject: String,
+        headers: HeaderMap,
+        payload: Bytes,
+        timeout: Duration,
+    ) -> Result<CoreMessage, RequestError>;
+
+    /// Publishes the response to the reply subject of the request.
+    async fn respond(
+        &self,
+        request: &CoreMessage,
+        headers: Headers,
+        payload: Vec<u8>,
+    ) -> Result<(), PublishError> {
+        self.respond_with_headers(request, headers.into(), payload.into())
+            .await
+    }
+
+    /// Same as `respond`, but the headers aren't limited to the event envelope.
+    async fn respond_with_headers(
+        &self,
+        request: &CoreMessage,
+        headers: HeaderMap,
+        payload: Bytes,
+    ) -> Result<(), PublishError>;
 }
@@ -0,0 +1,84 @@
+//! Prometheus metrics of the publisher and the consumer.
+//! Collectors aren't registered anywhere until [`register`] is called.
+
+use std::sync::LazyLock;
+
+use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry};
+
+pub(crate) static PUBLISH_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    HistogramVec::new(
+        HistogramOpts::new(
+            "nats_publish_duration_seconds",
+            "Time to publish a message and get the ack",
+        ),
+        &["prefix"],
+    )
+    .expect("invalid metric")
+});
+
+pub(crate) static PUBLISH_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new("nats_publish_errors_total", "Failed publishes"),
+        &["prefix"],
+    )
+    .expect("invalid metric")
+});
+
+pub(crate) static CONSUMER_MESSAGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "nats_consumer_messages_total",
+            "Consumed messages by outcome: consumed, acked, nacked, terminated",
+        ),
+        &["outcome"],
+    )
+    .expect("invalid metric")
+});
+
+pub(crate) static HANDLER_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    HistogramVec::new(
+        HistogramOpts::new(
+            "nats_consumer_handler_duration_seconds",
+            "Time spent in the message handler by result: ok, transient, permanent",
+        ),
+        &["result"],
+    )
+    .expect("invalid metric")
+});
+
+pub(crate) static SUSPENDS: LazyLock<IntCounter> = LazyLock::new(|| {
+    IntCounter::new(
+        "nats_consumer_suspends_total",
+        "Times the consumer suspended processing",
+    )
+    .expect("invalid metric")
+});
+
+pub(crate) static RESUBSCRIBES: LazyLock<IntCounter> = LazyLock::new(|| {
+    IntCounter::new(
+        "nats_consumer_resubscribes_total",
+        "Attempts to resubscribe to the durable consumer",
+    )
+    .expect("invalid metric")
+});
+
+pub(crate) static SENTRY_THROTTLED: LazyLock<IntCounter> = LazyLock::new(|| {
+    IntCounter::new(
+        "nats_consumer_sentry_throttled_total",
+        "Errors that weren't sent to sentry because of throttling",
+    )
+    .expect("invalid metric")
+});
+
+/// Registers the metrics in the registry, e.g. `prometheus::default_registry()`.
+pub fn register(registry: &Registry) -> prometheus::Result<()> {
+    registry.register(Box::new(PUBLISH_DURATION.clone()))?;
+    registry.register(Box::new(PUBLISH_ERRORS.clone()))?;
+    registry.register(Box::new(CONSUMER_MESSAGES.clone()))?;
+    registry.register(Box::new(HANDLER_DURATION.clone()))?;
+    registry.register(Box::new(SUSPENDS.clone()))?;
+    registry.register(Box::new(RESUBSCRIBES.clone()))?;
+    registry.register(Box::new(SENTRY_THROTTLED.clone()))?;
+
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    consumer::{box_handler, BoxHandler, ComposedHandler, HandleResult, ShutdownSummary},
+    ConsumerConfig, ConsumerMessage, NatsClient, SubscribeError,
+};
+
+/// Runs around the message handler. A middleware can inspect the message,
+/// short-circuit by not calling `next` and map the outcome of the rest of the chain.
+#[async_trait::async_trait]
+pub trait Middleware<M>: Send + Sync + 'static {
+    async fn handle(&self, message: Arc<M>, next: Next<'_, M>) -> HandleResult;
+}
+
+/// The rest of the middleware chain followed by the handler.
+pub struct Next<'a, M> {
+    middlewares: &'a [Arc<dyn Middleware<M>>],
+    handler: &'a BoxHandler<M>,
+}
+
+impl<'a, M: ConsumerMessage> Next<'a, M> {
+    pub async fn run(self, message: Arc<M>) -> HandleResult {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => {
+                let next = Next {
+                    middlewares,
+                    handler: self.handler,
+                };
+                middleware.handle(message, next).await
+            }
+            None => (self.handler)(message).await,
+        }
+    }
+}
+
+/// A handler wrapped into middlewares. The middleware added first runs first.
+pub struct Stack<M> {
+    middlewares: Vec<Arc<dyn Middleware<M>>>,
+    handler: BoxHandler<M>,
+}
+
+impl<M: ConsumerMessage> Stack<M> {
+    pub fn new<H, Fut>(handler: H) -> Self
+    where
+        H: Fn(Arc<M>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = HandleResult> + Send + 'static,
+    {
+        Self {
+            middlewares: vec![],
+            handler: box_handler(handler),
+        }
+    }
+
+    pub fn layer(mut self, middleware: impl Middleware<M>) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    pub async fn handle(&self, message: Arc<M>) -> HandleResult {
+        let next = Next {
+            middlewares: &self.middlewares,
+            handler: &self.handler,
+        };
+        next.run(message).await
+    }
+
+    /// Turns the stack into a handler for `consumer::run`.
+    pub fn into_handler(
+        self,
+    ) -> impl Fn(Arc<M>) -> BoxFuture<'static, HandleResult> + Send + Sync + 'static {
+        ComposedHandler::into_handler(self)
+    }
+
+    /// Runs a durable consumer with this stack as the handler.
+    pub fn run<C>(
+        self,
+        nats_client: C,
+        cfg: ConsumerConfig,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> JoinHandle<Result<ShutdownSummary, SubscribeError>>
+    where
+        C: NatsClient<M> + 'static,
+    {
+        ComposedHandler::run(self, nats_client, cfg, shutdown_rx)
+    }
+}
+
+#[async_trait::async_trait]
+impl<M: ConsumerMessage> ComposedHandler<M> for Stack<M> {
+    async fn handle(&self, message: Arc<M>) -> HandleResult {
+        Stack::handle(self, message).await
+    }
+}
+
+#[cfg(feature = "tower")]
+pub use self::tower::{from_service, HandlerService};
+
+#[cfg(feature = "tower")]
+mod tower {
+    use std::{
+        sync::Arc,
+        task::{Context, Poll},
+    };
+
+    use futures::future::BoxFuture;
+    use tower_service::Service;
+
+    use crate::{
+        consumer::{box_handler, BoxHandler, HandleMessageFailure, HandleResult},
+        ConsumerMessage,
+    };
+
+    /// A message handler as a `tower::Service`, so `tower` layers can be applied to it.
+    pub struct HandlerService<M> {
+        handler: Arc<BoxHandler<M>>,
+    }
+
+    impl<M> Clone for HandlerService<M> {
+        fn clone(&self) -> Self {
+            Self {
+                handler: self.handler.clone(),
+            }
+        }
+    }
+
+    impl<M: ConsumerMessage> HandlerService<M> {
+        pub fn new<H, Fut>(handler: H) -> Self
+        where
+            H: Fn(Arc<M>) -> Fut + Send + Sync + 'static,
+            Fut: std::future::Future<Output = HandleResult> + Send + 'static,
+        {
+            Self {
+                handler: Arc::new(box_handler(handler)),
+            }
+        }
+    }
+
+    impl<M: ConsumerMessage> Service<Arc<M>> for HandlerService<M> {
+        type Response = ();
+        type Error = HandleMessageFailure<anyhow::Error>;
+        type Future = BoxFuture<'static, HandleResult>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, message: Arc<M>) -> Self::Future {
+            (self.handler)(message)
+        }
+    }
+
+    /// Turns a `tower::Service` into a handler for `consumer::run`.
+    pub fn from_service<M, S>(
+        service: S,
+    ) -> impl Fn(Arc<M>) -> BoxFuture<'static, HandleResult> + Send + Sync + 'static
+    where
+        M: ConsumerMessage,
+        S: Service<Arc<M>, Response = (), Error = HandleMessageFailure<anyhow::Error>>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        S::Future: Send,
+    {
+        move |message| {
+            let mut service = service.clone();
+            Box::pin(async move {
+                futures::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+                service.call(message).await
+            })
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use std::fmt::Debug;
+
+use async_nats::jetstream::{
+    consumer::{self, pull, IntoConsumerConfig},
+    context::{CreateStreamError, GetStreamError, GetStreamErrorKind},
+    response::Response,
+    stream::{self, ConsumerError, Stream},
+    Context, ErrorCode,
+};
+use tracing::{info, warn};
+
+use crate::{Client, PullConsumerConfig, StreamConfig};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProvisionError {
+    #[error("provision config is not found")]
+    ProvisionConfigNotFound,
+    #[error("failed to get stream `{0}`: `{1}`")]
+    GettingStreamFailed(String, GetStreamError),
+    #[error("failed to create stream `{0}`: `{1}`")]
+    StreamCreationFailed(String, CreateStreamError),
+    #[error("failed to update stream `{0}`: `{1}`")]
+    StreamUpdateFailed(String, CreateStreamError),
+    #[error("failed to get consumer `{0}`: `{1}`")]
+    GettingConsumerFailed(String, async_nats::Error),
+    #[error("failed to create consumer `{0}`: `{1}`")]
+    ConsumerCreationFailed(String, ConsumerError),
+}
+
+/// A field whose live value differs from the declared one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff {
+    pub field: &'static str,
+    pub live: String,
+    pub declared: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Stream(String),
+    Consumer { stream: String, consumer: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Created,
+    Updated,
+    /// The live config differs, but updates are disabled.
+    Differs,
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub target: Target,
+    pub action: Action,
+    pub diff: Vec<Diff>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProvisionReport {
+    pub changes: Vec<Change>,
+}
+
+impl Client {
+    /// Creates the streams and durable pull consumers declared in `Config::provision`.
+    /// The existing ones are compared with the declared config and updated if
+    /// `ProvisionConfig::update` is set.
+    pub async fn provision(&self) -> Result<ProvisionReport, ProvisionError> {
+        let config = self
+            .config()
+            .provision
+            .as_ref()
+            .ok_or(ProvisionError::ProvisionConfigNotFound)?;
+
+        let mut report = ProvisionReport::default();
+
+        for declared in &config.streams {
+            let stream = self
+                .provision_stream(declared, config.update, &mut report)
+                .await?;
+
+            for consumer in &declared.consumers {
+                provision_consumer(
+                    self.jetstream(),
+                    &stream,
+                    declared,
+                    consumer,
+                    config.update,
+                    &mut report,
+                )
+                .await?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn provision_stream(
+        &self,
+        declared: &StreamConfig,
+        update: bool,
+        report: &mut ProvisionReport,
+    ) -> Result<Stream, ProvisionError> {
+        let target = Target::Stream(declared.name.clone());
+
+        let mut stream = match self.jetstream().get_stream(&declared.name).await {
+            Ok(stream) => stream,
+            Err(err) if is_stream_not_found(&err) => {
+                let config = apply_stream(declared, stream::Config::default());
+                let stream = self
+                    .jetstream()
+                    .create_stream(config)
+                    .await
+                    .map_err(|err| {
+                        ProvisionError::StreamCreationFailed(declared.name.clone(), err)
+                    })?;
+
+                info!(stream = declared.name, "nats stream created");
+                report.changes.push(Change {
+                    target,
+                    action: Action::Created,
+                    diff: vec![],
+                });
+                return Ok(stream);
+            }
+            Err(err) => {
+                return Err(ProvisionError::GettingStreamFailed(
+                    declared.name.clone(),
+                    err,
+                ))
+            }
+        };
+
+        let live = stream.cached_info().config.clone();
+        let diff = stream_diff(declared, &live);
+        if diff.is_empty() {
+            return Ok(stream);
+        }
+
+        let action = if update {
+            self.jetstream()
+                .update_stream(apply_stream(declared, live))
+                .await
+                .map_err(|err| ProvisionError::StreamUpdateFailed(declared.name.clone(), err))?;
+            // Refresh the cached info
+            stream
+                .info()
+                .await
+                .map_err(|err| warn!(%err, "failed to refresh nats stream info"))
+                .ok();
+            Action::Updated
+        } else {
+            Action::Differs
+        };
+
+        warn!(
+            stream = declared.name,
+            ?action,
+            ?diff,
+            "nats stream config differs"
+        );
+        report.changes.push(Change {
+            target,
+            action,
+            diff,
+        });
+
+        Ok(stream)
+    }
+}
+
+async fn provision_consumer(
+    jetstream: &Context,
+    stream: &Stream,
+    stream_config: &StreamConfig,
+    declared: &PullConsumerConfig,
+    update: bool,
+    report: &mut ProvisionReport,
+) -> Result<(), ProvisionError> {
+    let target = Target::Consumer {
+        stream: stream_config.name.clone(),
+        consumer: declared.name.clone(),
+    };
+
+    let info = consumer_info(jetstream, &stream_config.name, &declared.name)
+        .await
+        .map_err(|err| ProvisionError::GettingConsumerFailed(declared.name.clone(), err))?;
+
+    let (live, action, diff) = match info {
+        Some(info) => {
+            let diff = consumer_diff(declared, &info.config);
+            if diff.is_empty() {
+                return Ok(());
+            }
+
+            let action = if update {
+                Action::Updated
+            } else {
+                Action::Differs
+            };
+
+            warn!(
+                stream = stream_config.name,
+                consumer = declared.name,
+                ?action,
+                ?diff,
+                "nats consumer config differs"
+            );
+
+            if !update {
+                report.changes.push(Change {
+                    target,
+                    action,
+                    diff,
+                });
+                return Ok(());
+            }
+
+            (info.config, action, diff)
+        }
+        None => {
+            let config = pull::Config::default().into_consumer_config();
+            (config, Action::Created, vec![])
+        }
+    };
+
+    stream
+        .create_consumer(apply_consumer(declared, live))
+        .await
+        .map_err(|err| ProvisionError::ConsumerCreationFailed(declared.name.clone(), err))?;
+
+    if action == Action::Created {
+        info!(
+            stream = stream_config.name,
+            consumer = declared.name,
+            "nats consumer created"
+        );
+    }
+    report.changes.push(Change {
+        target,
+        action,
+        diff,
+    });
+
+    Ok(())
+}
+
+/// Requests the consumer info directly, since `Stream::consumer_info` drops the error code
+/// and a missing consumer can't be told apart from other failures.
+async fn consumer_info(
+    jetstream: &Context,
+    stream: &str,
+    consumer: &str,
+) -> Result<Option<consumer::Info>, async_nats::Error> {
+    let subject = format!("CONSUMER.INFO.{stream}.{consumer}");
+
+    match jetstream.request(subject, &serde_json::json!({})).await? {
+        Response::Ok(info) => Ok(Some(info)),
+        Response::Err { error } if error.error_code() == ErrorCode::CONSUMER_NOT_FOUND => Ok(None),
+        Response::Err { error } => Err(Box::new(error)),
+    }
+}
+
+fn is_stream_not_found(err: &GetStreamError) -> bool {
+    matches!(
+        err.kind(),
+        GetStreamErrorKind::JetStream(err) if err.error_code() == ErrorCode::STREAM_NOT_FOUND
+    )
+}
+
+fn apply_stream(declared: &StreamConfig, mut config: stream::Config) -> stream::Config {
+    config.name = declared.name.clone();
+    config.subjects = declared.subjects.clone();
+    if let Some(retention) = declared.retention {
+        config.retention = retention;
+    }
+    if let Some(max_age) = declared.max_age {
+        config.max_age = max_age;
+    }
+    if let Some(replicas) = declared.replicas {
+        config.num_replicas = replicas;
+    }
+    if let Some(duplicate_window) = declared.duplicate_window {
+        config.duplicate_window = duplicate_window;
+    }
+    config
+}
+
+fn apply_consumer(declared: &PullConsumerConfig, mut config: consumer::Config) -> consumer::Config {
+    config.durable_name = Some(declared.name.clone());
+    if let Some(filter_subject) = &declared.filter_subject {
+        config.filter_subject = filter_subject.clone();
+    }
+    if let Some(ack_wait) = declared.ack_wait {
+        config.ack_wait = ack_wait;
+    }
+    if let Some(max_deliver) = declared.max_deliver {
+        config.max_deliver = max_deliver;
+    }
+    if let Some(max_ack_pending) = declared.max_ack_pending {
+        config.max_ack_pending = max_ack_pending;
+    }
+    config
+}
+
+fn stream_diff(declared: &StreamConfig, live: &stream::Config) -> Vec<Diff> {
+    let mut diff = vec![];
+
+    let mut live_subjects = live.subjects.clone();
+    let mut declared_subjects = declared.subjects.clone();
+    live_subjects.sort();
+    declared_subjects.sort();
+    push_diff(
+        &mut diff,
+        "subjects",
+        &live_subjects,
+        Some(&declared_subjects),
+    );
+    push_diff(
+        &mut diff,
+        "retention",
+        &live.retention,
+        declared.retention.as_ref(),
+    );
+    push_diff(
+        &mut diff,
+        "max_age",
+        &live.max_age,
+        declared.max_age.as_ref(),
+    );
+    push_diff(
+        &mut diff,
+        "replicas",
+        &live.num_replicas,
+        declared.replicas.as_ref(),
+    );
+    push_diff(
+        &mut diff,
+        "duplicate_window",
+        &live.duplicate_window,
+        declared.duplicate_window.as_ref(),
+    );
+
+    diff
+}
+
+fn consumer_diff(declared: &PullConsumerConfig, live: &consumer::Config) -> Vec<Diff> {
+    let mut diff = vec![];
+
+    push_diff(
+        &mut diff,
+        "filter_subject",
+        &live.filter_subject,
+        declared.filter_subject.as_ref(),
+    );
+    push_diff(
+        &mut diff,
+        "ack_wait",
+        &live.ack_wait,
+        declared.ack_wait.as_ref(),
+    );
+    push_diff(
+        &mut diff,
+        "max_deliver",
+        &live.max_deliver,
+        declared.max_deliver.as_ref(),
+    );
+    push_diff(
+        &mut diff,
+        "max_ack_pending",
+        &live.max_ack_pending,
+        declared.max_ack_pending.as_ref(),
+    );
+
+    diff
+}
+
+fn push_diff<T: PartialEq + Debug>(
+    diff: &mut Vec<Diff>,
+    field: &'static str,
+    live: &T,
+    declared: Option<&T>,
+) {
+    match declared {
+        Some(declared) if declared != live => diff.push(Diff {
+            field,
+            live: format!("{live:?}"),
+            declared: format!("{declared:?}"),
+        }),
+        _ => {}
+    }
+}
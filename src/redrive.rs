@@ -0,0 +1,205 @@
+use std::{
+    num::NonZeroU32,
+    time::{Duration, SystemTime},
+};
+
+use async_nats::jetstream::{
+    consumer::{pull, pull::MessagesError, AckPolicy, DeliverPolicy, PullConsumer, StreamError},
+    context::GetStreamError,
+    stream::ConsumerError,
+};
+use futures_util::StreamExt;
+use uuid::Uuid;
+
+use crate::{
+    dead_letter::{self, DeadLetter},
+    Client, ConsumerMessage, PublishError, Subject,
+};
+
+/// Filters and limits for replaying dead-lettered messages.
+#[derive(Debug, Clone, Default)]
+pub struct RedriveOptions {
+    /// Filter on the original subject, wildcards are allowed (`classroom.*.room`).
+    pub subject: Option<String>,
+    pub classroom_id: Option<Uuid>,
+    /// Only messages dead-lettered at or after this time.
+    pub since: Option<SystemTime>,
+    /// Only messages dead-lettered before this time.
+    pub until: Option<SystemTime>,
+    /// Only report what would be republished.
+    pub dry_run: bool,
+    /// Max number of messages republished per second.
+    pub rate_limit: Option<NonZeroU32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RedriveReport {
+    pub matched: usize,
+    pub republished: usize,
+    /// Messages which couldn't be parsed or were dropped by the stream as duplicates.
+    pub skipped: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RedriveError {
+    #[error("config for dead-letter stream is not found")]
+    DeadLetterConfigNotFound,
+    #[error("failed to get stream: `{0}`")]
+    GettingStreamFailed(GetStreamError),
+    #[error("failed to create consumer: `{0}`")]
+    ConsumerCreationFailed(ConsumerError),
+    #[error("failed to create stream of messages: `{0}`")]
+    StreamCreationFailed(StreamError),
+    #[error("failed to get message: `{0}`")]
+    GettingMessageFailed(MessagesError),
+    #[error(transparent)]
+    PublishError(#[from] PublishError),
+}
+
+/// Reads the dead-letter stream from `Config::dead_letter` and republishes
+/// the matching messages to their original subjects with the original headers.
+/// Replayed messages are left in the dead-letter stream.
+pub async fn run(client: &Client, options: &RedriveOptions) -> Result<RedriveReport, RedriveError> {
+    let config = client
+        .config()
+        .dead_letter
+        .as_ref()
+        .ok_or(RedriveError::DeadLetterConfigNotFound)?;
+    let prefix = config.subject_prefix.as_str();
+
+    let stream = client
+        .jetstream()
+        .get_stream(&config.stream)
+        .await
+        .map_err(RedriveError::GettingStreamFailed)?;
+
+    let consumer_name = format!("redrive-{}", nuid::next());
+    let deliver_policy = match options.since {
+        Some(since) => DeliverPolicy::ByStartTime {
+            start_time: since.into(),
+        },
+        None => DeliverPolicy::All,
+    };
+
+    let consumer: PullConsumer = stream
+        .create_consumer(pull::Config {
+            name: Some(consumer_name.clone()),
+            filter_subject: format!("{}.{}", prefix, options.subject.as_deref().unwrap_or(">")),
+            deliver_policy,
+            ack_policy: AckPolicy::None,
+            inactive_threshold: Duration::from_secs(60),
+            ..Default::default()
+        })
+        .await
+        .map_err(RedriveError::ConsumerCreationFailed)?;
+
+    let mut report = RedriveReport::default();
+
+    let result = if consumer.cached_info().num_pending > 0 {
+        redrive_messages(client, &consumer, prefix, options, &mut report).await
+    } else {
+        Ok(())
+    };
+
+    if let Err(err) = stream.delete_consumer(&consumer_name).await {
+        tracing::warn!(%err, "failed to delete redrive consumer");
+    }
+
+    result.map(|()| report)
+}
+
+async fn redrive_messages(
+    client: &Client,
+    consumer: &PullConsumer,
+    prefix: &str,
+    options: &RedriveOptions,
+    report: &mut RedriveReport,
+) -> Result<(), RedriveError> {
+    let mut messages = consumer
+        .messages()
+        .await
+        .map_err(RedriveError::StreamCreationFailed)?;
+
+    let mut rate_limit = options.rate_limit.map(|rate| {
+        let mut interval = tokio::time::interval(Duration::from_secs(1) / rate.get());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval
+    });
+
+    while let Some(message) = messages.next().await {
+        let message = message.map_err(RedriveError::GettingMessageFailed)?;
+        let info = ConsumerMessage::info(&message);
+        let is_last = message.info().map_or(true, |info| info.pending == 0);
+
+        // Messages terminated before the failure metadata was added only have the subject
+        let dead_letter = DeadLetter::from_message(&message).ok();
+        let subject = match &dead_letter {
+            Some(dead_letter) => dead_letter.subject(),
+            None => Subject::from_dead_letter(&message.subject, prefix),
+        };
+        let failed_at = dead_letter
+            .as_ref()
+            .map(|dead_letter| dead_letter.failed_at())
+            .or(info.map(|info| info.published));
+
+        let subject = match subject {
+            Ok(subject) => subject,
+            Err(err) => {
+                tracing::warn!(%err, subject = %message.subject, "skipping dead-lettered message");
+                report.skipped += 1;
+
+                if is_last {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let is_matched = options
+            .classroom_id
+            .is_none_or(|classroom_id| subject.classroom_id() == classroom_id)
+            && match (options.since, failed_at) {
+                (Some(since), Some(failed_at)) => failed_at >= since,
+                _ => true,
+            }
+            && match (options.until, failed_at) {
+                (Some(until), Some(failed_at)) => failed_at < until,
+                _ => true,
+            };
+
+        if is_matched {
+            report.matched += 1;
+
+            if options.dry_run {
+                tracing::info!(%subject, "dry run: would republish dead-lettered message");
+            } else {
+                if let Some(interval) = rate_limit.as_mut() {
+                    interval.tick().await;
+                }
+
+                let headers = message
+                    .headers
+                    .as_ref()
+                    .map(dead_letter::original_headers)
+                    .unwrap_or_default();
+
+                let ack = client
+                    .publish_with_headers(subject.to_string(), headers, message.payload.clone())
+                    .await?;
+                if ack.duplicate {
+                    // The original message id is still in the stream's duplicate window
+                    tracing::warn!(%subject, "dead-lettered message was dropped as a duplicate");
+                    report.skipped += 1;
+                } else {
+                    report.republished += 1;
+                }
+            }
+        }
+
+        if is_last {
+            break;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::anyhow;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    consumer::{
+        box_handler, BoxHandler, ComposedHandler, HandleMessageFailure, HandleResult,
+        ShutdownSummary,
+    },
+    headers::{ENTITY_EVENT_OPERATION, ENTITY_EVENT_TYPE},
+    ConsumerConfig, ConsumerMessage, Message, NatsClient, SubscribeError,
+};
+
+/// What to do with a message that doesn't match any route.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fallback {
+    /// Ack the message and skip it.
+    #[default]
+    Ack,
+    /// Terminate the message as a permanent failure.
+    Terminate,
+    /// Treat the message as a transient failure so it's redelivered.
+    Transient,
+}
+
+/// Dispatches consumed messages to handlers by `Entity-Event-Type`/`Entity-Event-Operation`
+/// headers or by subject pattern. Event routes are checked first, then subject patterns
+/// in the order they were added.
+pub struct Router<M = Message> {
+    events: HashMap<(String, String), BoxHandler<M>>,
+    subjects: Vec<(String, BoxHandler<M>)>,
+    fallback: Fallback,
+}
+
+impl<M: ConsumerMessage> Default for Router<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: ConsumerMessage> Router<M> {
+    pub fn new() -> Self {
+        Self {
+            events: HashMap::new(),
+            subjects: vec![],
+            fallback: Fallback::default(),
+        }
+    }
+
+    /// Adds a handler for events with the given entity type and operation.
+    pub fn route<H, Fut>(mut self, entity_type: &str, operation: &str, handler: H) -> Self
+    where
+        H: Fn(Arc<M>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = HandleResult> + Send + 'static,
+    {
+        self.events.insert(
+            (entity_type.to_owned(), operation.to_owned()),
+            box_handler(handler),
+        );
+        self
+    }
+
+    /// Adds a handler for subjects matching the pattern.
+    /// `*` matches a single token, `>` matches the rest of the subject.
+    pub fn route_subject<H, Fut>(mut self, pattern: &str, handler: H) -> Self
+    where
+        H: Fn(Arc<M>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = HandleResult> + Send + 'static,
+    {
+        self.subjects
+            .push((pattern.to_owned(), box_handler(handler)));
+        self
+    }
+
+    pub fn fallback(self, fallback: Fallback) -> Self {
+        Self { fallback, ..self }
+    }
+
+    pub async fn handle(&self, message: Arc<M>) -> HandleResult {
+        let handler = match self.find(message.as_ref()) {
+            Some(handler) => handler,
+            None => {
+                let e = anyhow!("no route for message, subject: {}", message.subject());
+
+                return match self.fallback {
+                    Fallback::Ack => {
+                        tracing::warn!(%e);
+                        Ok(())
+                    }
+                    Fallback::Terminate => Err(HandleMessageFailure::Permanent(e)),
+                    Fallback::Transient => Err(HandleMessageFailure::Transient(e)),
+                };
+            }
+        };
+
+        handler(message).await
+    }
+
+    /// Turns the router into a handler for `consumer::run`.
+    pub fn into_handler(
+        self,
+    ) -> impl Fn(Arc<M>) -> BoxFuture<'static, HandleResult> + Send + Sync + 'static {
+        ComposedHandler::into_handler(self)
+    }
+
+    /// Runs a durable consumer with this router as the handler.
+    pub fn run<C>(
+        self,
+        nats_client: C,
+        cfg: ConsumerConfig,
+        shutdown_rx: watch::Receiver<()>,
+    ) -> JoinHandle<Result<ShutdownSummary, SubscribeError>>
+    where
+        C: NatsClient<M> + 'static,
+    {
+        ComposedHandler::run(self, nats_client, cfg, shutdown_rx)
+    }
+
+    fn find(&self, message: &M) -> Option<&BoxHandler<M>> {
+        let event = message.headers().and_then(|headers| {
+            let entity_type = headers.get(ENTITY_EVENT_TYPE)?.as_str();
+            let operation = headers.get(ENTITY_EVENT_OPERATION)?.as_str();
+            self.events
+                .get(&(entity_type.to_owned(), operation.to_owned()))
+        });
+
+        event.or_else(|| {
+            self.subjects
+                .iter()
+                .find(|(pattern, _)| subject_matches(pattern, message.subject()))
+                .map(|(_, handler)| handler)
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<M: ConsumerMessage> ComposedHandler<M> for Router<M> {
+    async fn handle(&self, message: Arc<M>) -> HandleResult {
+        Router::handle(self, message).await
+    }
+}
+
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject = subject.split('.');
+
+    for token in pattern.split('.') {
+        match (token, subject.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (token, Some(s)) if token == s => {}
+            _ => return false,
+        }
+    }
+
+    subject.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_nats::HeaderMap;
+
+    use super::*;
+    use crate::{consumer::FailureKind, test_helpers::TestMessage};
+
+    fn event(subject: &str, entity_type: &str, operation: &str) -> Arc<TestMessage> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ENTITY_EVENT_TYPE, entity_type);
+        headers.insert(ENTITY_EVENT_OPERATION, operation);
+        Arc::new(TestMessage::new(subject, vec![]).with_headers(headers))
+    }
+
+    fn routed(name: &'static str) -> impl Fn(Arc<TestMessage>) -> BoxFuture<'static, HandleResult> {
+        move |_| Box::pin(async move { Err(anyhow!(name)).permanent() })
+    }
+
+    async fn route_of(router: &Router<TestMessage>, message: Arc<TestMessage>) -> Option<String> {
+        match router.handle(message).await {
+            Err(HandleMessageFailure::Permanent(e)) => Some(e.to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn full_wildcard_needs_at_least_one_token() {
+        assert!(subject_matches("a.>", "a.b"));
+        assert!(subject_matches("a.>", "a.b.c"));
+        assert!(!subject_matches("a.>", "a"));
+    }
+
+    #[test]
+    fn token_wildcard_matches_single_token() {
+        assert!(subject_matches("a.*", "a.b"));
+        assert!(!subject_matches("a.*", "a.b.c"));
+        assert!(!subject_matches("a.*", "a"));
+    }
+
+    #[tokio::test]
+    async fn event_route_takes_precedence_over_subject_route() {
+        let router = Router::new().route_subject("a.>", routed("subject")).route(
+            "room",
+            "created",
+            routed("event"),
+        );
+
+        let route = route_of(&router, event("a.b", "room", "created")).await;
+        assert_eq!(route.as_deref(), Some("event"));
+
+        let route = route_of(&router, event("a.b", "room", "deleted")).await;
+        assert_eq!(route.as_deref(), Some("subject"));
+    }
+
+    #[tokio::test]
+    async fn fallback_acks_unrouted_message() {
+        let router = Router::<TestMessage>::new().fallback(Fallback::Ack);
+
+        let result = router.handle(event("a.b", "room", "created")).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn fallback_terminates_unrouted_message() {
+        let router = Router::<TestMessage>::new().fallback(Fallback::Terminate);
+
+        let result = router.handle(event("a.b", "room", "created")).await;
+        assert!(matches!(result, Err(HandleMessageFailure::Permanent(_))));
+    }
+
+    #[tokio::test]
+    async fn fallback_sends_back_unrouted_message() {
+        let router = Router::<TestMessage>::new().fallback(Fallback::Transient);
+
+        let result = router.handle(event("a.b", "room", "created")).await;
+        assert!(matches!(result, Err(HandleMessageFailure::Transient(_))));
+    }
+}
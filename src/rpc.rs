@@ -0,0 +1,261 @@
+//! Typed request-reply over core NATS.
+//!
+//! Requests are sent to `rpc.<receiver agent id>.<method>` with the usual event headers,
+//! the response carries the `Rpc-Status` header with an HTTP status code. Failed responses
+//! contain a `svc_error::Error` problem as the payload.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_nats::HeaderMap;
+use futures::future::BoxFuture;
+use futures_util::StreamExt;
+use http::StatusCode;
+use serde::{de::DeserializeOwned, Serialize};
+use svc_agent::AgentId;
+use svc_error::Error as SvcError;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::error;
+
+use crate::{
+    client::RequestError,
+    headers::{Builder as HeadersBuilder, Headers},
+    CoreMessage, CoreNatsClient, SubscribeError,
+};
+
+const RPC_PREFIX: &str = "rpc";
+const RPC_ENTITY_TYPE: &str = "rpc";
+const RPC_STATUS: &str = "Rpc-Status";
+
+/// A request which can be sent with `call` and handled by `RpcServer`.
+pub trait RpcRequest: Serialize + DeserializeOwned + Send + 'static {
+    /// Method name, must be a single subject token.
+    const METHOD: &'static str;
+
+    type Response: Serialize + DeserializeOwned + Send + 'static;
+}
+
+/// Sends the request to the `receiver` and waits for the response.
+pub async fn call<C, R>(
+    client: &C,
+    sender: &AgentId,
+    receiver: &AgentId,
+    request: &R,
+    timeout: Duration,
+) -> Result<R::Response, SvcError>
+where
+    C: CoreNatsClient + ?Sized,
+    R: RpcRequest,
+{
+    let payload = serde_json::to_vec(request)
+        .map_err(|err| problem("rpc_invalid_request", StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    let headers = rpc_headers(R::METHOD, sender.clone(), receiver.clone());
+
+    let response = client
+        .request_with_headers(
+            subject(receiver, R::METHOD),
+            headers.into(),
+            payload.into(),
+            timeout,
+        )
+        .await
+        .map_err(request_problem)?;
+
+    let status = response
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(RPC_STATUS))
+        .and_then(|status| status.as_str().parse::<u16>().ok())
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or_else(|| {
+            problem(
+                "rpc_invalid_response",
+                StatusCode::BAD_GATEWAY,
+                "rpc status is not found",
+            )
+        })?;
+
+    if status.is_success() {
+        serde_json::from_slice(&response.payload)
+            .map_err(|err| problem("rpc_invalid_response", StatusCode::BAD_GATEWAY, err))
+    } else {
+        let mut err: SvcError = serde_json::from_slice(&response.payload)
+            .map_err(|err| problem("rpc_invalid_response", StatusCode::BAD_GATEWAY, err))?;
+        err.set_status_code(status);
+        Err(err)
+    }
+}
+
+type BoxHandler =
+    Box<dyn Fn(&[u8], Headers) -> BoxFuture<'static, Result<Vec<u8>, SvcError>> + Send + Sync>;
+
+/// Handles the requests sent to `agent_id`.
+pub struct RpcServer {
+    agent_id: AgentId,
+    handlers: HashMap<&'static str, BoxHandler>,
+}
+
+impl RpcServer {
+    pub fn new(agent_id: AgentId) -> Self {
+        Self {
+            agent_id,
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Adds a handler for the request type.
+    pub fn handle<R, H, Fut>(mut self, handler: H) -> Self
+    where
+        R: RpcRequest,
+        H: Fn(R, Headers) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<R::Response, SvcError>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.handlers.insert(
+            R::METHOD,
+            Box::new(move |payload, headers| {
+                let request = serde_json::from_slice::<R>(payload);
+                let handler = handler.clone();
+                Box::pin(async move {
+                    let request = request.map_err(|err| {
+                        problem("rpc_invalid_request", StatusCode::BAD_REQUEST, err)
+                    })?;
+                    let response = handler(request, headers).await?;
+                    serde_json::to_vec(&response).map_err(|err| {
+                        problem(
+                            "rpc_invalid_response",
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            err,
+                        )
+                    })
+                })
+            }),
+        );
+        self
+    }
+
+    /// Subscribes to the requests and handles each of them in a separate task
+    /// until `shutdown_rx` fires.
+    pub fn run<C>(
+        self,
+        client: C,
+        mut shutdown_rx: watch::Receiver<()>,
+    ) -> JoinHandle<Result<(), SubscribeError>>
+    where
+        C: CoreNatsClient + Clone + 'static,
+    {
+        tokio::spawn(async move {
+            let subject = subject(&self.agent_id, "*");
+            let queue_group = self.agent_id.to_string();
+            let mut requests = client.subscribe_core(&subject, Some(&queue_group)).await?;
+            let server = Arc::new(self);
+
+            loop {
+                tokio::select! {
+                    request = requests.next() => {
+                        let Some(request) = request else {
+                            break;
+                        };
+
+                        let server = server.clone();
+                        let client = client.clone();
+                        tokio::spawn(async move { server.respond(&client, request).await });
+                    }
+                    // Dropping the subscription unsubscribes
+                    _ = shutdown_rx.changed() => break,
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn respond<C: CoreNatsClient>(&self, client: &C, request: CoreMessage) {
+        let method = request.subject.rsplit('.').next().unwrap_or_default();
+
+        let headers = match request.headers.clone().map(Headers::try_from) {
+            Some(Ok(headers)) => Ok(headers),
+            Some(Err(err)) => Err(problem("rpc_invalid_request", StatusCode::BAD_REQUEST, err)),
+            None => Err(problem(
+                "rpc_invalid_request",
+                StatusCode::BAD_REQUEST,
+                "headers are not found",
+            )),
+        };
+
+        let (result, caller) = match headers {
+            Ok(headers) => {
+                let caller = headers.sender_id().clone();
+                let result = match self.handlers.get(method) {
+                    Some(handler) => handler(&request.payload, headers).await,
+                    None => Err(problem(
+                        "rpc_method_not_found",
+                        StatusCode::NOT_FOUND,
+                        format!("method `{method}` is not found"),
+                    )),
+                };
+                (result, Some(caller))
+            }
+            Err(err) => (Err(err), None),
+        };
+
+        let (status, payload) = match result {
+            Ok(payload) => (StatusCode::OK, payload),
+            Err(err) => (
+                err.status_code(),
+                serde_json::to_vec(&err).unwrap_or_default(),
+            ),
+        };
+
+        let mut headers = HeadersBuilder::new(
+            (RPC_ENTITY_TYPE.to_owned(), method.to_owned(), 0).into(),
+            self.agent_id.clone(),
+        )
+        .enable_deduplication(false);
+        if let Some(caller) = caller {
+            headers = headers.receiver_id(caller);
+        }
+        let mut headers = HeaderMap::from(headers.build());
+        headers.insert(RPC_STATUS, status.as_u16().to_string().as_str());
+
+        if let Err(err) = client
+            .respond_with_headers(&request, headers, payload.into())
+            .await
+        {
+            error!(%err, method, "failed to send rpc response");
+        }
+    }
+}
+
+fn subject(receiver: &AgentId, method: &str) -> String {
+    format!("{RPC_PREFIX}.{receiver}.{method}")
+}
+
+fn rpc_headers(method: &str, sender: AgentId, receiver: AgentId) -> Headers {
+    HeadersBuilder::new(
+        (RPC_ENTITY_TYPE.to_owned(), method.to_owned(), 0).into(),
+        sender,
+    )
+    .receiver_id(receiver)
+    .enable_deduplication(false)
+    .build()
+}
+
+fn request_problem(err: RequestError) -> SvcError {
+    match err {
+        RequestError::TimedOut => problem("rpc_timeout", StatusCode::GATEWAY_TIMEOUT, err),
+        RequestError::NoResponders => {
+            problem("rpc_no_responders", StatusCode::SERVICE_UNAVAILABLE, err)
+        }
+        RequestError::RequestFailed(_) => {
+            problem("rpc_request_failed", StatusCode::BAD_GATEWAY, err)
+        }
+    }
+}
+
+fn problem(kind: &str, status: StatusCode, detail: impl std::fmt::Display) -> SvcError {
+    SvcError::builder()
+        .kind(kind, "RPC failed")
+        .status(status)
+        .detail(&detail.to_string())
+        .build()
+}
@@ -30,6 +30,16 @@ impl Subject {
     pub fn entity_type(&self) -> &str {
         &self.entity_type
     }
+
+    /// Parses the original subject of a message published to
+    /// `<dead_letter_prefix>.<prefix>.<classroom_id>.<entity_type>`.
+    pub fn from_dead_letter(subject: &str, dead_letter_prefix: &str) -> Result<Self, SubjectError> {
+        subject
+            .strip_prefix(dead_letter_prefix)
+            .and_then(|subject| subject.strip_prefix('.'))
+            .ok_or(SubjectError::DeadLetterPrefixNotFound)?
+            .parse()
+    }
 }
 
 impl std::fmt::Display for Subject {
@@ -50,6 +60,8 @@ pub enum SubjectError {
     ClassroomIdNotFound,
     #[error("failed to get entity_type from the subject")]
     EntityTypeNotFound,
+    #[error("failed to get dead-letter prefix from the subject")]
+    DeadLetterPrefixNotFound,
     #[error(transparent)]
     ClassroomIdParseFailed(#[from] uuid::Error),
 }
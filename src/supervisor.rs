@@ -0,0 +1,199 @@
+use std::{collections::HashMap, sync::Arc, task::Poll};
+
+use async_nats::jetstream::consumer::{AckPolicy, DeliverPolicy};
+use futures_util::StreamExt;
+use tokio::{
+    sync::watch,
+    task::{JoinError, JoinHandle},
+};
+
+use crate::{
+    consumer::{self, HandleResult, ShutdownSummary},
+    Client, ConsumerConfig, Event, Message, MessageStream, Messages, NatsClient, PublishAck,
+    PublishError, Subject, SubscribeError, TermMessageError,
+};
+
+/// State of a supervised consumer.
+#[derive(Debug, Clone)]
+pub enum ConsumerHealth {
+    /// The consumer hasn't subscribed yet.
+    Starting,
+    /// The consumer receives messages.
+    Subscribed,
+    /// The last subscription attempt failed, the consumer will try again.
+    SubscriptionFailed(String),
+    /// The message stream was closed, the consumer will resubscribe.
+    StreamClosed,
+    /// The consumer has completed its work.
+    Stopped,
+}
+
+impl ConsumerHealth {
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, Self::Subscribed)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SupervisorError {
+    #[error(transparent)]
+    SubscribeFailed(#[from] SubscribeError),
+    #[error("consumer task failed: `{0}`")]
+    TaskFailed(JoinError),
+    #[error("consumer `{0}` is already spawned")]
+    DuplicateName(String),
+}
+
+/// Runs `consumer::run` for several named durable subscriptions from `Config::consumers`
+/// with a shared shutdown signal.
+pub struct Supervisor {
+    client: Client,
+    shutdown_rx: watch::Receiver<()>,
+    consumers: HashMap<String, Supervised>,
+}
+
+struct Supervised {
+    health: watch::Receiver<ConsumerHealth>,
+    handle: JoinHandle<Result<ShutdownSummary, SubscribeError>>,
+}
+
+impl Supervisor {
+    pub fn new(client: Client, shutdown_rx: watch::Receiver<()>) -> Self {
+        Self {
+            client,
+            shutdown_rx,
+            consumers: HashMap::new(),
+        }
+    }
+
+    /// Starts a consumer for the named subscription, each name can be spawned once.
+    pub fn spawn<H, Fut>(
+        &mut self,
+        name: &str,
+        cfg: ConsumerConfig,
+        handle_message: H,
+    ) -> Result<(), SupervisorError>
+    where
+        H: Fn(Arc<Message>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = HandleResult> + Send + 'static,
+    {
+        if self.consumers.contains_key(name) {
+            return Err(SupervisorError::DuplicateName(name.to_owned()));
+        }
+
+        let (health_tx, health_rx) = watch::channel(ConsumerHealth::Starting);
+        let client = HealthReporter {
+            client: self.client.with_durable(name)?,
+            health: Arc::new(health_tx),
+        };
+        let handle = consumer::run(client, cfg, self.shutdown_rx.clone(), handle_message);
+
+        self.consumers.insert(
+            name.to_owned(),
+            Supervised {
+                health: health_rx,
+                handle,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Returns the current state of every consumer.
+    pub fn health(&self) -> HashMap<String, ConsumerHealth> {
+        self.consumers
+            .iter()
+            .map(|(name, consumer)| {
+                let health = if consumer.handle.is_finished() {
+                    ConsumerHealth::Stopped
+                } else {
+                    consumer.health.borrow().clone()
+                };
+
+                (name.clone(), health)
+            })
+            .collect()
+    }
+
+    /// Returns a receiver of the state changes of the named consumer.
+    pub fn watch(&self, name: &str) -> Option<watch::Receiver<ConsumerHealth>> {
+        self.consumers
+            .get(name)
+            .map(|consumer| consumer.health.clone())
+    }
+
+    /// Waits for every consumer to complete its work.
+    pub async fn join(self) -> HashMap<String, Result<ShutdownSummary, SupervisorError>> {
+        let mut results = HashMap::new();
+
+        for (name, consumer) in self.consumers {
+            let result = match consumer.handle.await {
+                Ok(result) => result.map_err(SupervisorError::from),
+                Err(err) => Err(SupervisorError::TaskFailed(err)),
+            };
+
+            results.insert(name, result);
+        }
+
+        results
+    }
+}
+
+/// Reports subscription results of the inner client to the supervisor.
+struct HealthReporter {
+    client: Client,
+    health: Arc<watch::Sender<ConsumerHealth>>,
+}
+
+#[async_trait::async_trait]
+impl NatsClient for HealthReporter {
+    async fn publish(&self, event: &Event) -> Result<PublishAck, PublishError> {
+        self.client.publish(event).await
+    }
+
+    async fn publish_batch(
+        &self,
+        events: &[Event],
+        max_pending: usize,
+    ) -> Vec<Result<PublishAck, PublishError>> {
+        self.client.publish_batch(events, max_pending).await
+    }
+
+    async fn subscribe_durable(&self) -> Result<MessageStream, SubscribeError> {
+        let mut messages = match self.client.subscribe_durable().await {
+            Ok(messages) => messages,
+            Err(err) => {
+                self.health
+                    .send_replace(ConsumerHealth::SubscriptionFailed(err.to_string()));
+                return Err(err);
+            }
+        };
+        self.health.send_replace(ConsumerHealth::Subscribed);
+
+        let health = self.health.clone();
+        let stream = futures::stream::poll_fn(move |cx| {
+            let poll = messages.poll_next_unpin(cx);
+            if let Poll::Ready(None) = poll {
+                health.send_replace(ConsumerHealth::StreamClosed);
+            }
+            poll
+        });
+
+        Ok(MessageStream::new(stream))
+    }
+
+    async fn subscribe_ephemeral(
+        &self,
+        subject: Subject,
+        deliver_policy: DeliverPolicy,
+        ack_policy: AckPolicy,
+    ) -> Result<Messages, SubscribeError> {
+        self.client
+            .subscribe_ephemeral(subject, deliver_policy, ack_policy)
+            .await
+    }
+
+    async fn terminate(&self, message: &Message, reason: &str) -> Result<(), TermMessageError> {
+        self.client.terminate(message, reason).await
+    }
+}
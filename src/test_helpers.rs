@@ -1,20 +1,76 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 
-use async_nats::jetstream::{
-    consumer::{push::Messages, AckPolicy, DeliverPolicy},
-    Message,
+use async_nats::{
+    jetstream::{
+        consumer::{push::Messages, AckPolicy, DeliverPolicy},
+        AckKind, Message,
+    },
+    HeaderMap,
 };
+use bytes::Bytes;
 
 use crate::{
-    event::Event, MessageStream, NatsClient, PublishError, Subject, SubscribeError,
+    event::Event, ConsumerMessage, CoreMessage, CoreMessageStream, CoreNatsClient, MessageInfo,
+    MessageStream, NatsClient, PublishAck, PublishError, RequestError, Subject, SubscribeError,
     TermMessageError,
 };
 
 pub use crate::headers::Builder as HeadersBuilder;
 
-pub struct TestNatsClient {
+/// A core NATS subscription made with `TestNatsClient::subscribe_core`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreSubscription {
+    pub subject: String,
+    pub queue_group: Option<String>,
+}
+
+/// A request sent with `TestNatsClient::request`.
+#[derive(Debug, Clone)]
+pub struct CoreRequest {
+    pub subject: String,
+    pub headers: HeaderMap,
+    pub payload: Bytes,
+}
+
+/// A response sent with `TestNatsClient::respond`.
+#[derive(Debug, Clone)]
+pub struct CoreResponse {
+    pub request: CoreMessage,
+    pub headers: HeaderMap,
+    pub payload: Bytes,
+}
+
+pub struct TestNatsClient<M = Message> {
     publish_requests: Arc<RwLock<Vec<Event>>>,
-    terminate_requests: Arc<RwLock<Vec<Message>>>,
+    core_publish_requests: Arc<RwLock<Vec<Event>>>,
+    terminate_requests: Arc<RwLock<Vec<M>>>,
+    durable_messages: Arc<Mutex<Vec<M>>>,
+    core_subscriptions: Arc<RwLock<Vec<CoreSubscription>>>,
+    core_messages: Arc<Mutex<Vec<CoreMessage>>>,
+    requests: Arc<RwLock<Vec<CoreRequest>>>,
+    request_responses: Arc<Mutex<VecDeque<CoreMessage>>>,
+    responses: Arc<RwLock<Vec<CoreResponse>>>,
+}
+
+/// Clones share recorded requests, so a clone can be moved into `consumer::run`.
+impl<M> Clone for TestNatsClient<M> {
+    fn clone(&self) -> Self {
+        Self {
+            publish_requests: self.publish_requests.clone(),
+            core_publish_requests: self.core_publish_requests.clone(),
+            terminate_requests: self.terminate_requests.clone(),
+            durable_messages: self.durable_messages.clone(),
+            core_subscriptions: self.core_subscriptions.clone(),
+            core_messages: self.core_messages.clone(),
+            requests: self.requests.clone(),
+            request_responses: self.request_responses.clone(),
+            responses: self.responses.clone(),
+        }
+    }
 }
 
 impl Default for TestNatsClient {
@@ -25,28 +81,88 @@ impl Default for TestNatsClient {
 
 impl TestNatsClient {
     pub fn new() -> Self {
+        Self::with_messages(vec![])
+    }
+}
+
+impl<M> TestNatsClient<M> {
+    /// Creates a client whose durable subscription yields the given messages once.
+    pub fn with_messages(messages: Vec<M>) -> Self {
         Self {
             publish_requests: Arc::new(RwLock::new(vec![])),
+            core_publish_requests: Arc::new(RwLock::new(vec![])),
             terminate_requests: Arc::new(RwLock::new(vec![])),
+            durable_messages: Arc::new(Mutex::new(messages)),
+            core_subscriptions: Arc::new(RwLock::new(vec![])),
+            core_messages: Arc::new(Mutex::new(vec![])),
+            requests: Arc::new(RwLock::new(vec![])),
+            request_responses: Arc::new(Mutex::new(VecDeque::new())),
+            responses: Arc::new(RwLock::new(vec![])),
         }
     }
 
+    /// The next core subscription yields the given messages once.
+    pub fn with_core_messages(self, messages: Vec<CoreMessage>) -> Self {
+        *self
+            .core_messages
+            .lock()
+            .expect("failed to get lock on core messages") = messages;
+        self
+    }
+
+    /// Requests get the given responses in order, `RequestError::NoResponders` after that.
+    pub fn with_request_responses(self, responses: Vec<CoreMessage>) -> Self {
+        *self
+            .request_responses
+            .lock()
+            .expect("failed to get lock on request responses") = responses.into();
+        self
+    }
+
     pub fn get_publish_requests(&self) -> std::sync::RwLockReadGuard<'_, Vec<Event>> {
         self.publish_requests
             .read()
             .expect("failed to get read lock on publish reqs")
     }
 
-    pub fn get_terminate_requests(&self) -> std::sync::RwLockReadGuard<'_, Vec<Message>> {
+    pub fn get_core_publish_requests(&self) -> std::sync::RwLockReadGuard<'_, Vec<Event>> {
+        self.core_publish_requests
+            .read()
+            .expect("failed to get read lock on core publish reqs")
+    }
+
+    pub fn get_terminate_requests(&self) -> std::sync::RwLockReadGuard<'_, Vec<M>> {
         self.terminate_requests
             .read()
             .expect("failed to get read lock on terminate reqs")
     }
+
+    pub fn get_core_subscriptions(&self) -> std::sync::RwLockReadGuard<'_, Vec<CoreSubscription>> {
+        self.core_subscriptions
+            .read()
+            .expect("failed to get read lock on core subscriptions")
+    }
+
+    pub fn get_requests(&self) -> std::sync::RwLockReadGuard<'_, Vec<CoreRequest>> {
+        self.requests
+            .read()
+            .expect("failed to get read lock on requests")
+    }
+
+    pub fn get_responses(&self) -> std::sync::RwLockReadGuard<'_, Vec<CoreResponse>> {
+        self.responses
+            .read()
+            .expect("failed to get read lock on responses")
+    }
 }
 
 #[async_trait::async_trait]
-impl NatsClient for TestNatsClient {
-    async fn publish(&self, event: &Event) -> Result<(), PublishError> {
+impl<M> NatsClient<M> for TestNatsClient<M>
+where
+    M: Clone + Send + Sync + 'static,
+{
+    /// Acks every event with the next sequence number of the `test` stream.
+    async fn publish(&self, event: &Event) -> Result<PublishAck, PublishError> {
         let mut reqs = self
             .publish_requests
             .write()
@@ -54,11 +170,24 @@ impl NatsClient for TestNatsClient {
 
         reqs.push(event.clone());
 
-        Ok(())
+        Ok(PublishAck {
+            stream: "test".to_owned(),
+            sequence: reqs.len() as u64,
+            ..Default::default()
+        })
     }
 
-    async fn subscribe_durable(&self) -> Result<MessageStream, SubscribeError> {
-        unimplemented!("this is test client")
+    async fn subscribe_durable(&self) -> Result<MessageStream<M>, SubscribeError> {
+        let messages = std::mem::take(
+            &mut *self
+                .durable_messages
+                .lock()
+                .expect("failed to get lock on durable messages"),
+        );
+
+        Ok(MessageStream::new(futures::stream::iter(
+            messages.into_iter().map(Ok),
+        )))
     }
 
     async fn subscribe_ephemeral(
@@ -70,7 +199,7 @@ impl NatsClient for TestNatsClient {
         unimplemented!("this is test client")
     }
 
-    async fn terminate(&self, message: &Message) -> Result<(), TermMessageError> {
+    async fn terminate(&self, message: &M, _reason: &str) -> Result<(), TermMessageError> {
         let mut reqs = self
             .terminate_requests
             .write()
@@ -81,3 +210,156 @@ impl NatsClient for TestNatsClient {
         Ok(())
     }
 }
+
+#[async_trait::async_trait]
+impl<M> CoreNatsClient for TestNatsClient<M>
+where
+    M: Send + Sync + 'static,
+{
+    async fn publish_core(&self, event: &Event) -> Result<(), PublishError> {
+        let mut reqs = self
+            .core_publish_requests
+            .write()
+            .expect("failed to get write lock on core publish reqs");
+
+        reqs.push(event.clone());
+
+        Ok(())
+    }
+
+    async fn subscribe_core(
+        &self,
+        subject: &str,
+        queue_group: Option<&str>,
+    ) -> Result<CoreMessageStream, SubscribeError> {
+        self.core_subscriptions
+            .write()
+            .expect("failed to get write lock on core subscriptions")
+            .push(CoreSubscription {
+                subject: subject.to_owned(),
+                queue_group: queue_group.map(ToOwned::to_owned),
+            });
+
+        let messages = std::mem::take(
+            &mut *self
+                .core_messages
+                .lock()
+                .expect("failed to get lock on core messages"),
+        );
+
+        Ok(CoreMessageStream::new(futures::stream::iter(messages)))
+    }
+
+    async fn request_with_headers(
+        &self,
+        subject: String,
+        headers: HeaderMap,
+        payload: Bytes,
+        _timeout: Duration,
+    ) -> Result<CoreMessage, RequestError> {
+        self.requests
+            .write()
+            .expect("failed to get write lock on requests")
+            .push(CoreRequest {
+                subject,
+                headers,
+                payload,
+            });
+
+        self.request_responses
+            .lock()
+            .expect("failed to get lock on request responses")
+            .pop_front()
+            .ok_or(RequestError::NoResponders)
+    }
+
+    async fn respond_with_headers(
+        &self,
+        request: &CoreMessage,
+        headers: HeaderMap,
+        payload: Bytes,
+    ) -> Result<(), PublishError> {
+        self.responses
+            .write()
+            .expect("failed to get write lock on responses")
+            .push(CoreResponse {
+                request: request.clone(),
+                headers,
+                payload,
+            });
+
+        Ok(())
+    }
+}
+
+/// In-memory message for driving `consumer::run` without a server.
+/// Clones share the list of received acks.
+#[derive(Clone)]
+pub struct TestMessage {
+    subject: String,
+    payload: Vec<u8>,
+    headers: Option<HeaderMap>,
+    info: Option<MessageInfo>,
+    acks: Arc<RwLock<Vec<AckKind>>>,
+}
+
+impl TestMessage {
+    pub fn new(subject: impl Into<String>, payload: Vec<u8>) -> Self {
+        Self {
+            subject: subject.into(),
+            payload,
+            headers: None,
+            info: None,
+            acks: Arc::new(RwLock::new(vec![])),
+        }
+    }
+
+    pub fn with_headers(self, headers: HeaderMap) -> Self {
+        Self {
+            headers: Some(headers),
+            ..self
+        }
+    }
+
+    pub fn with_info(self, info: MessageInfo) -> Self {
+        Self {
+            info: Some(info),
+            ..self
+        }
+    }
+
+    pub fn get_acks(&self) -> std::sync::RwLockReadGuard<'_, Vec<AckKind>> {
+        self.acks.read().expect("failed to get read lock on acks")
+    }
+}
+
+#[async_trait::async_trait]
+impl ConsumerMessage for TestMessage {
+    fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    fn headers(&self) -> Option<&HeaderMap> {
+        self.headers.as_ref()
+    }
+
+    fn info(&self) -> Option<MessageInfo> {
+        self.info.clone()
+    }
+
+    async fn ack(&self) -> Result<(), async_nats::Error> {
+        self.ack_with(AckKind::Ack).await
+    }
+
+    async fn ack_with(&self, kind: AckKind) -> Result<(), async_nats::Error> {
+        let mut acks = self.acks.write().expect("failed to get write lock on acks");
+
+        acks.push(kind);
+
+        Ok(())
+    }
+}
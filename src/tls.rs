@@ -0,0 +1,68 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use async_nats::ConnectOptions;
+use rustls_pemfile::Item;
+
+use crate::{ConnectError, TlsConfig};
+
+/// Checks the certificate files up front, since the connection only
+/// reports a generic TLS error for them.
+pub(crate) fn configure(
+    mut options: ConnectOptions,
+    config: &TlsConfig,
+) -> Result<ConnectOptions, ConnectError> {
+    if let Some(ca) = &config.ca {
+        check_certificates(ca)?;
+        options = options.add_root_certificates(ca.to_owned());
+    }
+
+    match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            check_certificates(cert)?;
+            check_private_key(key)?;
+            options = options.add_client_certificate(cert.to_owned(), key.to_owned());
+        }
+        (None, None) => {}
+        _ => return Err(ConnectError::IncompleteClientCertificate),
+    }
+
+    Ok(options.require_tls(config.required))
+}
+
+fn check_certificates(path: &Path) -> Result<(), ConnectError> {
+    let items = read_pem(path)?;
+    if !items
+        .iter()
+        .any(|item| matches!(item, Item::X509Certificate(_)))
+    {
+        return Err(ConnectError::InvalidTlsFile(
+            path.to_owned(),
+            "no PEM certificates found",
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_private_key(path: &Path) -> Result<(), ConnectError> {
+    let items = read_pem(path)?;
+    if !items
+        .iter()
+        .any(|item| matches!(item, Item::RSAKey(_) | Item::PKCS8Key(_) | Item::ECKey(_)))
+    {
+        return Err(ConnectError::InvalidTlsFile(
+            path.to_owned(),
+            "no PEM private key found",
+        ));
+    }
+
+    Ok(())
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, ConnectError> {
+    let file =
+        File::open(path).map_err(|err| ConnectError::TlsFileUnreadable(path.to_owned(), err))?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| ConnectError::TlsFileUnreadable(path.to_owned(), err))
+}
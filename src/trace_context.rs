@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+
+use async_nats::HeaderMap;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::headers::{TRACEPARENT, TRACESTATE};
+
+/// Returns `traceparent` and `tracestate` of the current span.
+pub(crate) fn current() -> (Option<String>, Option<String>) {
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+
+    let traceparent = carrier.remove(TRACEPARENT);
+    let tracestate = carrier
+        .remove(TRACESTATE)
+        .filter(|tracestate| !tracestate.is_empty());
+
+    (traceparent, tracestate)
+}
+
+/// Makes the span a child of the producer's span from the message headers.
+pub(crate) fn set_parent(span: &Span, headers: &HeaderMap) {
+    let context = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    span.set_parent(context);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|value| value.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        vec![TRACEPARENT, TRACESTATE]
+    }
+}
@@ -0,0 +1,82 @@
+use std::{str::FromStr, sync::Arc};
+
+use anyhow::anyhow;
+use futures::future::{self, Either, Ready};
+use serde::de::DeserializeOwned;
+
+use crate::{consumer::HandleMessageFailure, ConsumerMessage, Headers, Subject};
+
+/// A consumed message with decoded payload, headers and subject.
+pub struct TypedMessage<T, M> {
+    payload: T,
+    headers: Headers,
+    subject: Subject,
+    message: Arc<M>,
+}
+
+impl<T, M> TypedMessage<T, M>
+where
+    T: DeserializeOwned,
+    M: ConsumerMessage,
+{
+    /// Decodes the message. Every decode failure is permanent since a redelivery won't fix it.
+    pub fn decode(message: Arc<M>) -> Result<Self, HandleMessageFailure<anyhow::Error>> {
+        let subject = Subject::from_str(message.subject())
+            .map_err(|e| HandleMessageFailure::Permanent(anyhow!(e).context("invalid subject")))?;
+
+        let headers = Headers::try_from(message.headers().cloned().unwrap_or_default())
+            .map_err(|e| HandleMessageFailure::Permanent(anyhow!(e).context("invalid headers")))?;
+
+        let payload = serde_json::from_slice(message.payload()).map_err(|e| {
+            HandleMessageFailure::Permanent(anyhow!(e).context("failed to deserialize payload"))
+        })?;
+
+        Ok(Self {
+            payload,
+            headers,
+            subject,
+            message,
+        })
+    }
+}
+
+impl<T, M> TypedMessage<T, M> {
+    pub fn payload(&self) -> &T {
+        &self.payload
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn subject(&self) -> &Subject {
+        &self.subject
+    }
+
+    /// The raw message, e.g. to get its JetStream metadata.
+    pub fn message(&self) -> &Arc<M> {
+        &self.message
+    }
+
+    pub fn into_payload(self) -> T {
+        self.payload
+    }
+}
+
+type DecodeFailed = Ready<Result<(), HandleMessageFailure<anyhow::Error>>>;
+
+/// Wraps a handler of decoded messages so it can be passed to `consumer::run`.
+pub fn handler<T, M, H, Fut>(
+    handle_message: H,
+) -> impl Fn(Arc<M>) -> Either<Fut, DecodeFailed> + Send + Sync + 'static
+where
+    T: DeserializeOwned,
+    M: ConsumerMessage,
+    H: Fn(TypedMessage<T, M>) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<(), HandleMessageFailure<anyhow::Error>>>,
+{
+    move |message| match TypedMessage::decode(message) {
+        Ok(message) => Either::Left(handle_message(message)),
+        Err(e) => Either::Right(future::ready(Err(e))),
+    }
+}
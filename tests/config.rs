@@ -0,0 +1,40 @@
+use svc_nats_client::{Auth, Config};
+
+#[test]
+fn deserializes_legacy_creds_as_credentials_file() {
+    let config: Config =
+        serde_json::from_str(r#"{"url":"nats://localhost:4222","creds":"/etc/nats.creds"}"#)
+            .unwrap();
+
+    assert!(matches!(
+        config.auth,
+        Auth::CredentialsFile { path } if path == "/etc/nats.creds"
+    ));
+}
+
+#[test]
+fn rejects_both_auth_and_creds() {
+    let config = serde_json::from_str::<Config>(
+        r#"{"url":"nats://localhost:4222","creds":"/etc/nats.creds","auth":{"method":"none"}}"#,
+    );
+
+    assert!(config.is_err());
+}
+
+#[test]
+fn redacts_secrets_in_debug() {
+    let auth = Auth::UserPassword {
+        user: "user".to_owned(),
+        password: "secret".to_owned(),
+    };
+    let token = Auth::Token {
+        token: "secret".to_owned(),
+    };
+    let nkey = Auth::Nkey {
+        seed: "secret".to_owned(),
+    };
+
+    for auth in [auth, token, nkey] {
+        assert!(!format!("{auth:?}").contains("secret"));
+    }
+}
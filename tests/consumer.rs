@@ -0,0 +1,515 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use svc_nats_client::{
+    consumer::{self, FailureKind},
+    test_helpers::{TestMessage, TestNatsClient},
+    AckKind, ConsumerConfig, ConsumerMessage, MessageInfo, RedeliveryMode,
+};
+
+const CLASSROOM_A: &str = "test.00000000-0000-0000-0000-000000000001.entity";
+const CLASSROOM_B: &str = "test.00000000-0000-0000-0000-000000000002.entity";
+
+fn config() -> ConsumerConfig {
+    ConsumerConfig {
+        suspend_interval: Duration::from_millis(100),
+        max_suspend_interval: Duration::from_millis(100),
+        suspend_sentry_interval: Duration::from_secs(1),
+        resubscribe_interval: Duration::from_millis(10),
+        concurrency: 4,
+        redelivery: RedeliveryMode::Suspend,
+        max_attempts: None,
+        progress_interval: None,
+        handler_timeout: None,
+        panic_failure: Default::default(),
+        drain_timeout: Duration::from_secs(30),
+    }
+}
+
+fn message(subject: &str, payload: &str, stream_sequence: u64) -> TestMessage {
+    redelivered(subject, payload, stream_sequence, 1)
+}
+
+fn redelivered(subject: &str, payload: &str, stream_sequence: u64, delivered: i64) -> TestMessage {
+    TestMessage::new(subject, payload.as_bytes().to_vec()).with_info(MessageInfo {
+        stream: "test".to_owned(),
+        consumer: "test".to_owned(),
+        stream_sequence,
+        consumer_sequence: stream_sequence,
+        delivered,
+        published: SystemTime::now(),
+    })
+}
+
+fn delivered(message: &TestMessage) -> i64 {
+    message
+        .info()
+        .map(|info| info.delivered)
+        .unwrap_or_default()
+}
+
+fn payload(message: &TestMessage) -> String {
+    String::from_utf8_lossy(message.payload()).into_owned()
+}
+
+/// Runs the consumer over `messages` for `duration` and returns the handled payloads in order.
+async fn consume<H, Fut>(
+    messages: Vec<TestMessage>,
+    cfg: ConsumerConfig,
+    duration: Duration,
+    handle: H,
+) -> Vec<String>
+where
+    H: Fn(Arc<TestMessage>) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<(), consumer::HandleMessageFailure<anyhow::Error>>>
+        + Send,
+{
+    consume_with(
+        TestNatsClient::with_messages(messages),
+        cfg,
+        duration,
+        handle,
+    )
+    .await
+}
+
+/// Same as `consume`, but the client is kept by the caller to check the recorded requests.
+async fn consume_with<H, Fut>(
+    client: TestNatsClient<TestMessage>,
+    cfg: ConsumerConfig,
+    duration: Duration,
+    handle: H,
+) -> Vec<String>
+where
+    H: Fn(Arc<TestMessage>) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<(), consumer::HandleMessageFailure<anyhow::Error>>>
+        + Send,
+{
+    let handled = Arc::new(Mutex::new(vec![]));
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+
+    let log = handled.clone();
+    let handle = Arc::new(handle);
+    let task = consumer::run(
+        client,
+        cfg,
+        shutdown_rx,
+        move |message: Arc<TestMessage>| {
+            let log = log.clone();
+            let handle = handle.clone();
+            async move {
+                let result = handle(message.clone()).await;
+                log.lock().unwrap().push(payload(&message));
+                result
+            }
+        },
+    );
+
+    tokio::time::sleep(duration).await;
+    shutdown_tx.send(()).unwrap();
+    task.await.unwrap().unwrap();
+
+    let handled = handled.lock().unwrap().clone();
+    handled
+}
+
+#[tokio::test(start_paused = true)]
+async fn handles_messages_of_classroom_in_order() {
+    let messages = vec![
+        message(CLASSROOM_A, "a1", 1),
+        message(CLASSROOM_A, "a2", 2),
+        message(CLASSROOM_A, "a3", 3),
+    ];
+
+    let handled = consume(
+        messages.clone(),
+        config(),
+        Duration::from_millis(200),
+        |message| async move {
+            // The first message is the slowest one
+            if payload(&message) == "a1" {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            Ok(())
+        },
+    )
+    .await;
+
+    assert_eq!(handled, vec!["a1", "a2", "a3"]);
+    for message in &messages {
+        assert!(matches!(message.get_acks().as_slice(), [AckKind::Ack]));
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn handles_classrooms_concurrently() {
+    let messages = vec![
+        message(CLASSROOM_A, "a1", 1),
+        message(CLASSROOM_A, "a2", 2),
+        message(CLASSROOM_B, "b1", 3),
+    ];
+
+    let handled = consume(
+        messages,
+        config(),
+        Duration::from_millis(200),
+        |message| async move {
+            if payload(&message).starts_with('a') {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+            }
+            Ok(())
+        },
+    )
+    .await;
+
+    assert_eq!(handled, vec!["b1", "a1", "a2"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn handles_classrooms_one_by_one_without_concurrency() {
+    let messages = vec![message(CLASSROOM_A, "a1", 1), message(CLASSROOM_B, "b1", 2)];
+    let cfg = ConsumerConfig {
+        concurrency: 1,
+        ..config()
+    };
+
+    let handled = consume(
+        messages,
+        cfg,
+        Duration::from_millis(200),
+        |message| async move {
+            if payload(&message) == "a1" {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+            }
+            Ok(())
+        },
+    )
+    .await;
+
+    assert_eq!(handled, vec!["a1", "b1"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn suspends_and_sends_back_rest_of_classroom_on_transient_failure() {
+    let messages = vec![
+        message(CLASSROOM_A, "a1", 1),
+        message(CLASSROOM_A, "a2", 2),
+        message(CLASSROOM_B, "b1", 3),
+    ];
+
+    let cfg = ConsumerConfig {
+        suspend_interval: Duration::from_millis(50),
+        max_suspend_interval: Duration::from_secs(1),
+        ..config()
+    };
+
+    let handled = consume(
+        messages.clone(),
+        cfg,
+        Duration::from_millis(200),
+        |message| async move {
+            match payload(&message).as_str() {
+                "a1" => Err(anyhow::anyhow!("not ready")).transient(),
+                _ => Ok(()),
+            }
+        },
+    )
+    .await;
+
+    // `a2` must not overtake `a1` which will be redelivered
+    assert_eq!(handled, vec!["a1", "b1"]);
+    assert!(matches!(
+        messages[0].get_acks().as_slice(),
+        [AckKind::Nak(None)]
+    ));
+    assert!(matches!(
+        messages[1].get_acks().as_slice(),
+        [AckKind::Nak(None)]
+    ));
+    assert!(matches!(messages[2].get_acks().as_slice(), [AckKind::Ack]));
+}
+
+#[tokio::test(start_paused = true)]
+async fn holds_classroom_until_failed_message_is_redelivered() {
+    let messages = vec![
+        message(CLASSROOM_A, "a1", 1),
+        message(CLASSROOM_B, "b1", 2),
+        message(CLASSROOM_A, "a2", 3),
+        redelivered(CLASSROOM_A, "a1", 1, 2),
+    ];
+    // `a2` is fetched only after `a1` has failed
+    let cfg = ConsumerConfig {
+        redelivery: RedeliveryMode::Delay,
+        max_suspend_interval: Duration::from_secs(10),
+        concurrency: 1,
+        ..config()
+    };
+
+    let handled = consume(
+        messages.clone(),
+        cfg,
+        Duration::from_millis(100),
+        |message| async move {
+            match (payload(&message).as_str(), delivered(&message)) {
+                ("a1", 1) => Err(anyhow::anyhow!("not ready")).transient(),
+                _ => Ok(()),
+            }
+        },
+    )
+    .await;
+
+    assert_eq!(handled, vec!["a1", "b1", "a1", "a2"]);
+    assert!(matches!(
+        messages[0].get_acks().as_slice(),
+        [AckKind::Nak(Some(d))] if *d == Duration::from_millis(200)
+    ));
+    // Waiting for `a1` doesn't count as a delivery of `a2`
+    assert!(matches!(messages[2].get_acks().as_slice(), [AckKind::Ack]));
+    assert!(matches!(messages[3].get_acks().as_slice(), [AckKind::Ack]));
+}
+
+#[tokio::test(start_paused = true)]
+async fn message_held_behind_failed_one_has_all_attempts() {
+    let messages = vec![
+        message(CLASSROOM_A, "a1", 1),
+        message(CLASSROOM_A, "a2", 2),
+        redelivered(CLASSROOM_A, "a1", 1, 2),
+    ];
+    let client = TestNatsClient::with_messages(messages.clone());
+    let cfg = ConsumerConfig {
+        redelivery: RedeliveryMode::Delay,
+        max_suspend_interval: Duration::from_secs(10),
+        max_attempts: Some(2),
+        concurrency: 1,
+        ..config()
+    };
+
+    let handled = consume_with(
+        client.clone(),
+        cfg,
+        Duration::from_millis(100),
+        |message| async move {
+            match (payload(&message).as_str(), delivered(&message)) {
+                ("a1", 2) => Ok(()),
+                _ => Err(anyhow::anyhow!("not ready")).transient(),
+            }
+        },
+    )
+    .await;
+
+    assert_eq!(handled, vec!["a1", "a1", "a2"]);
+    // `a2` fails on its first delivery, so it's sent back rather than dead-lettered
+    assert!(client.get_terminate_requests().is_empty());
+    assert!(matches!(
+        messages[1].get_acks().as_slice(),
+        [AckKind::Nak(Some(_))]
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn terminates_message_on_transient_failure_of_last_attempt() {
+    let failed = redelivered(CLASSROOM_A, "a1", 1, 3);
+    let client = TestNatsClient::with_messages(vec![failed.clone()]);
+    let cfg = ConsumerConfig {
+        max_attempts: Some(3),
+        ..config()
+    };
+
+    consume_with(client.clone(), cfg, Duration::from_millis(50), |_| async {
+        Err(anyhow::anyhow!("not ready")).transient()
+    })
+    .await;
+
+    let terminated = client.get_terminate_requests();
+    assert_eq!(terminated.len(), 1);
+    assert_eq!(payload(&terminated[0]), "a1");
+    assert!(!failed
+        .get_acks()
+        .iter()
+        .any(|ack| matches!(ack, AckKind::Nak(_))));
+}
+
+#[tokio::test(start_paused = true)]
+async fn delays_redelivery_exponentially() {
+    let failed = TestMessage::new(CLASSROOM_A, vec![]).with_info(MessageInfo {
+        stream: "test".to_owned(),
+        consumer: "test".to_owned(),
+        stream_sequence: 1,
+        consumer_sequence: 1,
+        delivered: 2,
+        published: SystemTime::now(),
+    });
+    let cfg = ConsumerConfig {
+        redelivery: RedeliveryMode::Delay,
+        suspend_interval: Duration::from_millis(250),
+        max_suspend_interval: Duration::from_secs(10),
+        ..config()
+    };
+
+    consume(
+        vec![failed.clone()],
+        cfg,
+        Duration::from_millis(50),
+        |_| async { Err(anyhow::anyhow!("not ready")).transient() },
+    )
+    .await;
+
+    assert!(matches!(
+        failed.get_acks().as_slice(),
+        [AckKind::Nak(Some(d))] if *d == Duration::from_secs(1)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn terminates_message_on_panic_before_first_await() {
+    let messages = vec![message(CLASSROOM_A, "a1", 1), message(CLASSROOM_B, "b1", 2)];
+    let client = TestNatsClient::with_messages(messages.clone());
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+
+    let task = consumer::run(
+        client.clone(),
+        ConsumerConfig {
+            panic_failure: svc_nats_client::PanicFailure::Permanent,
+            ..config()
+        },
+        shutdown_rx,
+        |message: Arc<TestMessage>| {
+            if payload(&message) == "a1" {
+                panic!("boom");
+            }
+            async { Ok(()) }
+        },
+    );
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    shutdown_tx.send(()).unwrap();
+    task.await.unwrap().unwrap();
+
+    assert_eq!(client.get_terminate_requests().len(), 1);
+    assert!(matches!(messages[1].get_acks().as_slice(), [AckKind::Ack]));
+}
+
+#[tokio::test(start_paused = true)]
+async fn sends_progress_for_queued_messages() {
+    let messages = vec![message(CLASSROOM_A, "a1", 1), message(CLASSROOM_A, "a2", 2)];
+    let cfg = ConsumerConfig {
+        progress_interval: Some(Duration::from_millis(20)),
+        ..config()
+    };
+
+    consume(
+        messages.clone(),
+        cfg,
+        Duration::from_millis(200),
+        |message| async move {
+            if payload(&message) == "a1" {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Ok(())
+        },
+    )
+    .await;
+
+    for message in &messages {
+        let acks = message.get_acks();
+        let progress = acks
+            .iter()
+            .filter(|ack| matches!(ack, AckKind::Progress))
+            .count();
+        assert!(progress >= 3, "{acks:?}");
+        assert!(matches!(acks.last(), Some(AckKind::Ack)));
+    }
+}
+
+/// Runs the consumer over `messages` until shutdown after `duration` and returns its summary.
+async fn shutdown_summary<H, Fut>(
+    messages: Vec<TestMessage>,
+    cfg: ConsumerConfig,
+    duration: Duration,
+    handle: H,
+) -> consumer::ShutdownSummary
+where
+    H: Fn(Arc<TestMessage>) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<(), consumer::HandleMessageFailure<anyhow::Error>>>
+        + Send
+        + 'static,
+{
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+    let task = consumer::run(
+        TestNatsClient::with_messages(messages),
+        cfg,
+        shutdown_rx,
+        handle,
+    );
+
+    tokio::time::sleep(duration).await;
+    shutdown_tx.send(()).unwrap();
+    task.await.unwrap().unwrap()
+}
+
+#[tokio::test(start_paused = true)]
+async fn nacks_not_handled_messages_on_shutdown() {
+    let messages = vec![
+        message(CLASSROOM_A, "a1", 1),
+        message(CLASSROOM_A, "a2", 2),
+        message(CLASSROOM_A, "a3", 3),
+    ];
+    let cfg = ConsumerConfig {
+        concurrency: 1,
+        ..config()
+    };
+
+    let summary = shutdown_summary(
+        messages.clone(),
+        cfg,
+        Duration::from_millis(100),
+        |_message| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(())
+        },
+    )
+    .await;
+
+    assert_eq!(summary.completed, 1);
+    assert_eq!(summary.nacked, 2);
+    assert_eq!(summary.abandoned, 0);
+    assert!(matches!(messages[0].get_acks().as_slice(), [AckKind::Ack]));
+    for message in &messages[1..] {
+        assert!(matches!(
+            message.get_acks().as_slice(),
+            [AckKind::Nak(None)]
+        ));
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn abandons_handlers_running_past_drain_timeout() {
+    let messages = vec![message(CLASSROOM_A, "a1", 1), message(CLASSROOM_B, "b1", 2)];
+    let cfg = ConsumerConfig {
+        drain_timeout: Duration::from_secs(1),
+        ..config()
+    };
+
+    let summary = shutdown_summary(
+        messages.clone(),
+        cfg,
+        Duration::from_millis(100),
+        |message| async move {
+            if payload(&message) == "a1" {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            } else {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+            Ok(())
+        },
+    )
+    .await;
+
+    assert_eq!(summary.completed, 1);
+    assert_eq!(summary.abandoned, 1);
+    assert_eq!(summary.nacked, 0);
+    assert!(messages[0].get_acks().is_empty());
+    assert!(matches!(messages[1].get_acks().as_slice(), [AckKind::Ack]));
+}
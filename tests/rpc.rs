@@ -0,0 +1,156 @@
+use std::{str::FromStr, time::Duration};
+
+use async_nats::HeaderMap;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use svc_agent::AgentId;
+use svc_nats_client::{
+    rpc::{self, RpcRequest, RpcServer},
+    test_helpers::{CoreSubscription, HeadersBuilder, TestNatsClient},
+    CoreMessage,
+};
+
+#[derive(Serialize, Deserialize)]
+struct Ping {
+    n: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Pong {
+    n: u32,
+}
+
+impl RpcRequest for Ping {
+    const METHOD: &'static str = "ping";
+
+    type Response = Pong;
+}
+
+fn agent(id: &str) -> AgentId {
+    AgentId::from_str(id).unwrap()
+}
+
+fn core_message(subject: &str, headers: HeaderMap, payload: Vec<u8>) -> CoreMessage {
+    CoreMessage {
+        subject: subject.to_owned(),
+        reply: Some("_INBOX.test".to_owned()),
+        payload: payload.into(),
+        headers: Some(headers),
+        status: None,
+        description: None,
+        length: 0,
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn server_responds_with_handler_result() {
+    let server_id = agent("server.svc.example.org");
+    let headers = HeadersBuilder::new(
+        ("rpc".to_owned(), "ping".to_owned(), 0).into(),
+        agent("client.svc.example.org"),
+    )
+    .receiver_id(server_id.clone())
+    .build();
+    let request = core_message(
+        "rpc.server.svc.example.org.ping",
+        headers.into(),
+        br#"{"n":1}"#.to_vec(),
+    );
+    let client = TestNatsClient::new().with_core_messages(vec![request]);
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+
+    RpcServer::new(server_id)
+        .handle(|ping: Ping, _headers| async move { Ok(Pong { n: ping.n + 1 }) })
+        .run(client.clone(), shutdown_rx)
+        .await
+        .unwrap()
+        .unwrap();
+    // Responses are sent from separate tasks
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        *client.get_core_subscriptions(),
+        vec![CoreSubscription {
+            subject: "rpc.server.svc.example.org.*".to_owned(),
+            queue_group: Some("server.svc.example.org".to_owned()),
+        }]
+    );
+    let responses = client.get_responses();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(
+        responses[0].headers.get("Rpc-Status").map(|s| s.as_str()),
+        Some("200")
+    );
+    assert_eq!(&responses[0].payload[..], br#"{"n":2}"#);
+}
+
+#[tokio::test(start_paused = true)]
+async fn server_responds_with_problem_to_unknown_method() {
+    let server_id = agent("server.svc.example.org");
+    let headers = HeadersBuilder::new(
+        ("rpc".to_owned(), "pong".to_owned(), 0).into(),
+        agent("client.svc.example.org"),
+    )
+    .build();
+    let request = core_message(
+        "rpc.server.svc.example.org.pong",
+        headers.into(),
+        b"{}".to_vec(),
+    );
+    let client = TestNatsClient::new().with_core_messages(vec![request]);
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+
+    RpcServer::new(server_id)
+        .handle(|ping: Ping, _headers| async move { Ok(Pong { n: ping.n }) })
+        .run(client.clone(), shutdown_rx)
+        .await
+        .unwrap()
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let responses = client.get_responses();
+    assert_eq!(
+        responses[0].headers.get("Rpc-Status").map(|s| s.as_str()),
+        Some("404")
+    );
+}
+
+#[tokio::test]
+async fn call_returns_response() {
+    let mut headers = HeaderMap::new();
+    headers.insert("Rpc-Status", "200");
+    let response = core_message("_INBOX.test", headers, br#"{"n":2}"#.to_vec());
+    let client = TestNatsClient::new().with_request_responses(vec![response]);
+
+    let pong = rpc::call(
+        &client,
+        &agent("client.svc.example.org"),
+        &agent("server.svc.example.org"),
+        &Ping { n: 1 },
+        Duration::from_secs(1),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(pong.n, 2);
+    let requests = client.get_requests();
+    assert_eq!(requests[0].subject, "rpc.server.svc.example.org.ping");
+    assert_eq!(&requests[0].payload[..], br#"{"n":1}"#);
+}
+
+#[tokio::test]
+async fn call_fails_without_responders() {
+    let client = TestNatsClient::new();
+
+    let err = rpc::call(
+        &client,
+        &agent("client.svc.example.org"),
+        &agent("server.svc.example.org"),
+        &Ping { n: 1 },
+        Duration::from_secs(1),
+    )
+    .await
+    .unwrap_err();
+
+    assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+}